@@ -0,0 +1,47 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::control::Request;
+
+/// Monitor and reset selected connections on the network you're connected to
+#[derive(Debug, Parser)]
+#[command(name = "resetti", version, about)]
+pub struct Cli {
+    /// Config file to load
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Interface to sniff on, overrides `interface` in `[device]`
+    #[arg(short, long, global = true)]
+    pub interface: Option<String>,
+    /// trace|debug|info|warning|error|critical|off, overrides `log-level` in `[log]`
+    #[arg(short, long, global = true)]
+    pub log_level: Option<String>,
+    /// Build and log resets without sending them
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Sniff the interface and apply the filters (default)
+    Run,
+    /// Validate the config and print the filters it defines
+    CheckConfig,
+    /// List the interfaces available for capture
+    ListInterfaces,
+    /// Apply the filters to a saved capture instead of a live interface
+    Replay {
+        /// .pcap or .pcapng file to read packets from
+        pcap: PathBuf,
+    },
+    /// Send a request to the control socket of a running instance
+    Ctl {
+        /// Control socket, `socket` in `[control]` of the config by default
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        #[command(subcommand)]
+        request: Request,
+    },
+}
@@ -1,15 +1,31 @@
 use anyhow::Result;
 use regex::Regex;
-use serde::Deserialize;
-use std::fs::{File, OpenOptions};
-use std::{net::IpAddr, str::FromStr};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use toml::Value;
+use toml_edit::{ArrayOfTables, Document, Item, Table};
 
+use crate::cli::Cli;
 use crate::errors::ConfigError;
-use crate::filters::{Filter, HostFilter, MacAddr, PacketAction};
+use crate::expr;
+use crate::filters::{
+    sort_by_priority, Filter, HostFilter, HostGroup, MacAddr, PacketAction, PortFilter, PortRange,
+    TransportProto, UnresolvedPolicy,
+};
+use crate::ipset::IpRange;
+use crate::quota::Quota;
+use crate::schedule::Schedule;
 
-const CONFIG_FILE: &str = "./test_config.toml";
+const CONFIG_ENV_VAR: &str = "RESETTI_CONFIG";
+const SYSTEM_CONFIG_FILE: &str = "/etc/resetti/config.toml";
 const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_DNS_CACHE_SIZE: usize = 4096;
+const DEFAULT_RESOLVER_THREADS: usize = 4;
+const DEFAULT_MAX_FLOWS: usize = 65536;
 
 #[derive(Debug, Clone)]
 pub enum Interface {
@@ -20,7 +36,8 @@ pub enum Interface {
 #[derive(Debug)]
 pub struct LogConfig {
     pub log_level: Option<slog::Level>,
-    pub log_file: Option<File>,
+    /// Opened when logging starts, so reloading the config leaves it alone
+    pub log_file: Option<PathBuf>,
 }
 
 impl Default for LogConfig {
@@ -32,17 +49,58 @@ impl Default for LogConfig {
     }
 }
 
+#[derive(Debug)]
+pub struct DnsConfig {
+    /// Most addresses to keep a name for
+    pub cache_size: usize,
+    /// Threads running reverse lookups
+    pub resolver_threads: usize,
+    /// Where learned names are saved across restarts
+    pub state_file: Option<PathBuf>,
+    /// `/etc/hosts` style files seeding names
+    pub hosts_files: Vec<PathBuf>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: DEFAULT_DNS_CACHE_SIZE,
+            resolver_threads: DEFAULT_RESOLVER_THREADS,
+            state_file: None,
+            hosts_files: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
+    pub path: PathBuf,
     pub filter: Vec<Filter>,
     pub interface: Interface,
     pub log: LogConfig,
+    pub dns: DnsConfig,
+    /// Most connections tracked at once
+    pub max_flows: usize,
+    pub dry_run: bool,
+    /// Policy for packets whose filter waits on a reverse lookup
+    pub on_unresolved: UnresolvedPolicy,
+    /// Host groups, for filters added at runtime
+    pub groups: Groups,
+    /// Unix socket of the control API, disabled if unset
+    pub control_socket: Option<PathBuf>,
+    /// JSON Lines log of monitored and reset connections, `-` for stdout
+    pub events: Option<PathBuf>,
 }
 
 impl Config {
-    pub fn build() -> Result<Self, ConfigError> {
-        let contents =
-            std::fs::read_to_string(CONFIG_FILE).map_err(ConfigError::FailedToReadConfig)?;
+    /// Loads the config from `path`, or from the first existing file among
+    /// the standard locations if no path was given
+    pub fn build(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(p) => p.to_path_buf(),
+            None => find_config(|var| std::env::var_os(var))?,
+        };
+        let contents = std::fs::read_to_string(&path).map_err(ConfigError::FailedToReadConfig)?;
         let val = contents
             .parse::<Value>()
             .map_err(|e| ConfigError::FailedToParseConfig(e.to_string()))?;
@@ -52,10 +110,23 @@ impl Config {
 
         let interface = get_interface(table)?;
         let log = get_log(table)?;
+        let dns = get_dns(table)?;
+        let max_flows = get_max_flows(table)?;
+        let groups = Groups::parse(table.get("groups"))?;
+        let control_socket = get_control_socket(table)?;
+        let events = get_events(table)?;
+        let dry_run = table
+            .get("dry_run")
+            .map(bool_from_value)
+            .transpose()?
+            .unwrap_or(false);
+        let on_unresolved = table
+            .get("on_unresolved")
+            .map(policy_from_value)
+            .transpose()?
+            .unwrap_or(UnresolvedPolicy::Ignore);
 
-        let vals = table
-            .get("filter")
-            .ok_or_else(|| ConfigError::NoFiltersFound)?;
+        let vals = table.get("filter").ok_or(ConfigError::NoFiltersFound)?;
         let vals = vals.as_array().ok_or_else(|| {
             ConfigError::FailedToParseConfig("Failed to parse filters as an array".to_string())
         })?;
@@ -66,21 +137,74 @@ impl Config {
                 Ok(acc)
             },
         )?;
-        let mut filter: Vec<Filter> = vec.iter().try_fold(
+        let mut filter: Vec<Filter> = vec.iter().enumerate().try_fold(
             Vec::with_capacity(vec.len()),
-            |mut acc, i| -> Result<Vec<Filter>, ConfigError> {
-                acc.push(i.try_into()?);
+            |mut acc, (idx, i)| -> Result<Vec<Filter>, ConfigError> {
+                let mut f = i.to_filter(&groups)?;
+                f.rule = idx + 1;
+                acc.push(f);
                 Ok(acc)
             },
         )?;
-        filter.sort();
+        sort_by_priority(&mut filter);
 
         Ok(Self {
+            path,
             filter,
             interface,
             log,
+            dns,
+            max_flows,
+            dry_run,
+            on_unresolved,
+            groups,
+            control_socket,
+            events,
         })
     }
+
+    /// Overrides the values read from the config file with the ones given on
+    /// the command line
+    pub fn apply_cli(&mut self, cli: &Cli) -> Result<(), ConfigError> {
+        if let Some(i) = &cli.interface {
+            self.interface = Interface::Custom(i.clone());
+        }
+        if let Some(l) = &cli.log_level {
+            self.log.log_level = log_level_from_str(l)?;
+        }
+        if cli.dry_run {
+            // the flag is a safety switch, it wins over filters that opt out
+            self.dry_run = true;
+            self.filter.iter_mut().for_each(|f| f.dry_run = Some(true));
+        }
+        Ok(())
+    }
+}
+
+/// Config locations in lookup order:
+/// `$RESETTI_CONFIG`, `$XDG_CONFIG_HOME/resetti/config.toml`,
+/// `~/.config/resetti/config.toml` then `/etc/resetti/config.toml`
+fn config_candidates(env: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    let mut candidates = Vec::with_capacity(4);
+    if let Some(p) = env(CONFIG_ENV_VAR) {
+        candidates.push(PathBuf::from(p));
+    }
+    if let Some(p) = env("XDG_CONFIG_HOME") {
+        candidates.push(PathBuf::from(p).join("resetti/config.toml"));
+    }
+    if let Some(p) = env("HOME") {
+        candidates.push(PathBuf::from(p).join(".config/resetti/config.toml"));
+    }
+    candidates.push(PathBuf::from(SYSTEM_CONFIG_FILE));
+    candidates
+}
+
+fn find_config(env: impl Fn(&str) -> Option<OsString>) -> Result<PathBuf, ConfigError> {
+    let candidates = config_candidates(env);
+    match candidates.iter().find(|p| p.is_file()) {
+        Some(p) => Ok(p.clone()),
+        None => Err(ConfigError::NoConfigFound(candidates)),
+    }
 }
 
 fn get_interface(table: &toml::map::Map<String, Value>) -> Result<Interface, ConfigError> {
@@ -108,30 +232,13 @@ fn get_log(table: &toml::map::Map<String, Value>) -> Result<LogConfig, ConfigErr
                     .ok_or(ConfigError::FailedToParseAsString(v.clone()))?,
                 None => DEFAULT_LOG_LEVEL,
             };
-            let log_level = slog::Level::from_str(filter_level);
-            let log_level = match log_level {
-                Ok(s) => Ok(Some(s)),
-                Err(_) => {
-                    if filter_level == "off" {
-                        Ok(None)
-                    } else {
-                        Err(ConfigError::InvalidLogLevel(filter_level.to_string()))
-                    }
-                }
-            }?;
+            let log_level = log_level_from_str(filter_level)?;
             let log_file = match value.get("log-file") {
                 Some(v) => {
                     let st = v
                         .as_str()
                         .ok_or(ConfigError::FailedToParseAsString(v.clone()))?;
-                    Some(
-                        OpenOptions::new()
-                            .create(true)
-                            .write(true)
-                            .truncate(true)
-                            .open(st)
-                            .unwrap(),
-                    )
+                    Some(PathBuf::from(st))
                 }
                 None => None,
             };
@@ -144,34 +251,328 @@ fn get_log(table: &toml::map::Map<String, Value>) -> Result<LogConfig, ConfigErr
     }
 }
 
-#[derive(Deserialize, Debug)]
+fn get_control_socket(
+    table: &toml::map::Map<String, Value>,
+) -> Result<Option<PathBuf>, ConfigError> {
+    let Some(value) = table.get("control") else {
+        return Ok(None);
+    };
+    Ok(value
+        .get("socket")
+        .map(string_from_value)
+        .transpose()?
+        .map(PathBuf::from))
+}
+
+fn get_events(table: &toml::map::Map<String, Value>) -> Result<Option<PathBuf>, ConfigError> {
+    let Some(value) = table.get("events") else {
+        return Ok(None);
+    };
+    Ok(value
+        .get("file")
+        .map(string_from_value)
+        .transpose()?
+        .map(PathBuf::from))
+}
+
+fn get_max_flows(table: &toml::map::Map<String, Value>) -> Result<usize, ConfigError> {
+    match table.get("flows").and_then(|f| f.get("max")) {
+        Some(v) => v
+            .as_integer()
+            .and_then(|i| usize::try_from(i).ok())
+            .filter(|i| *i > 0)
+            .ok_or(ConfigError::InvalidMaxFlows(v.clone())),
+        None => Ok(DEFAULT_MAX_FLOWS),
+    }
+}
+
+fn get_dns(table: &toml::map::Map<String, Value>) -> Result<DnsConfig, ConfigError> {
+    let Some(value) = table.get("dns") else {
+        return Ok(DnsConfig::default());
+    };
+    let cache_size = match value.get("cache-size") {
+        Some(v) => v
+            .as_integer()
+            .and_then(|i| usize::try_from(i).ok())
+            .filter(|i| *i > 0)
+            .ok_or(ConfigError::InvalidCacheSize(v.clone()))?,
+        None => DEFAULT_DNS_CACHE_SIZE,
+    };
+    let resolver_threads = match value.get("resolver-threads") {
+        Some(v) => v
+            .as_integer()
+            .and_then(|i| usize::try_from(i).ok())
+            .filter(|i| *i > 0)
+            .ok_or(ConfigError::InvalidResolverThreads(v.clone()))?,
+        None => DEFAULT_RESOLVER_THREADS,
+    };
+    let state_file = value
+        .get("state-file")
+        .map(string_from_value)
+        .transpose()?
+        .map(PathBuf::from);
+    let hosts_files = match value.get("hosts-files") {
+        Some(v) => v
+            .as_array()
+            .ok_or(ConfigError::ExpectedAList)?
+            .iter()
+            .map(|f| string_from_value(f).map(PathBuf::from))
+            .collect::<Result<_, _>>()?,
+        None => Vec::new(),
+    };
+    Ok(DnsConfig {
+        cache_size,
+        resolver_threads,
+        state_file,
+        hosts_files,
+    })
+}
+
+/// Hosts of a group, with the ones of the groups it includes
+#[derive(Debug, Clone, Default)]
+struct Members {
+    ips: Vec<IpRange>,
+    macs: Vec<MacAddr>,
+    domains: Vec<String>,
+}
+
+impl Members {
+    fn extend(&mut self, other: &Members) {
+        self.ips.extend_from_slice(&other.ips);
+        self.macs.extend_from_slice(&other.macs);
+        self.domains.extend_from_slice(&other.domains);
+    }
+}
+
+/// Named host groups from the `[groups.<name>]` tables, each with the
+/// groups it includes flattened in
+#[derive(Debug, Clone, Default)]
+pub struct Groups(HashMap<String, Members>);
+
+impl Groups {
+    fn parse(value: Option<&Value>) -> Result<Self, ConfigError> {
+        let Some(value) = value else {
+            return Ok(Self::default());
+        };
+        let tables = value
+            .as_table()
+            .ok_or_else(|| ConfigError::InvalidGroup("groups".to_string()))?;
+        let mut raw = HashMap::with_capacity(tables.len());
+        for (name, group) in tables {
+            if !group.is_table() {
+                return Err(ConfigError::InvalidGroup(name.clone()));
+            }
+            let list = |key: &str| {
+                group
+                    .get(key)
+                    .map(|v| {
+                        v.as_array()
+                            .ok_or(ConfigError::ExpectedAList)?
+                            .iter()
+                            .map(string_from_value)
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .transpose()
+                    .map(Option::unwrap_or_default)
+            };
+            let members = Members {
+                ips: group
+                    .get("ips")
+                    .map(ip_vec_from_value)
+                    .transpose()?
+                    .unwrap_or_default(),
+                macs: group
+                    .get("macs")
+                    .map(mac_vec_from_value)
+                    .transpose()?
+                    .unwrap_or_default(),
+                domains: list("domains")?,
+            };
+            let include = list("include")?
+                .iter()
+                .map(|r| {
+                    group_ref(r)
+                        .map(str::to_string)
+                        .ok_or_else(|| ConfigError::UnknownGroup(r.clone()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            raw.insert(name.as_str(), (members, include));
+        }
+        let mut groups = Self::default();
+        for name in raw.keys() {
+            groups.flatten(name, &raw, &mut Vec::new())?;
+        }
+        Ok(groups)
+    }
+
+    /// Adds a group once the ones it includes are, `path` holds the groups
+    /// being flattened to catch cycles
+    fn flatten<'a>(
+        &mut self,
+        name: &'a str,
+        raw: &'a HashMap<&'a str, (Members, Vec<String>)>,
+        path: &mut Vec<&'a str>,
+    ) -> Result<(), ConfigError> {
+        if self.0.contains_key(name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|n| format!("@{n}")).collect();
+            cycle.push(format!("@{name}"));
+            return Err(ConfigError::CyclicGroup(cycle.join(" -> ")));
+        }
+        let (members, include) = raw
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownGroup(name.to_string()))?;
+        path.push(name);
+        let mut flat = members.clone();
+        for inner in include {
+            self.flatten(inner, raw, path)?;
+            flat.extend(&self.0[inner]);
+        }
+        path.pop();
+        self.0.insert(name.to_string(), flat);
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<&Members, ConfigError> {
+        self.0
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownGroup(name.to_string()))
+    }
+}
+
+/// Name of the group an `@name` entry refers to
+fn group_ref(entry: &str) -> Option<&str> {
+    entry.strip_prefix('@').filter(|n| !n.is_empty())
+}
+
+/// Value of a `src`, `dst`, `src_exclude` or `dst_exclude` key, a list of
+/// addresses and `@group` references or a single reference
+#[derive(Debug)]
+struct HostList {
+    ips: Vec<IpRange>,
+    groups: Vec<String>,
+}
+
+impl TryFrom<&Value> for HostList {
+    type Error = ConfigError;
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        let entries = match value {
+            Value::String(s) if group_ref(s).is_some() => std::slice::from_ref(value),
+            _ => value.as_array().ok_or(ConfigError::ExpectedAList)?,
+        };
+        let mut list = Self {
+            ips: Vec::new(),
+            groups: Vec::new(),
+        };
+        for entry in entries {
+            let s = string_from_value(entry)?;
+            match group_ref(&s) {
+                Some(name) => list.groups.push(name.to_string()),
+                None => list.ips.push(IpRange::try_from(s.as_str())?),
+            }
+        }
+        Ok(list)
+    }
+}
+
+impl HostList {
+    /// Plain address lists stay IP filters, lists referencing groups become
+    /// group filters
+    fn resolve(&self, groups: &Groups, include: bool) -> Result<HostFilter, ConfigError> {
+        if self.groups.is_empty() {
+            let set = self.ips.iter().copied().collect();
+            return Ok(match include {
+                true => HostFilter::IncludeIPs(set),
+                false => HostFilter::ExcludeIPs(set),
+            });
+        }
+        let mut members = Members {
+            ips: self.ips.clone(),
+            ..Members::default()
+        };
+        for name in &self.groups {
+            members.extend(groups.get(name)?);
+        }
+        let names: Vec<String> = self.groups.iter().map(|n| format!("@{n}")).collect();
+        // one regex per group entry, any of them matching is enough
+        let domains = match members.domains.as_slice() {
+            [] => None,
+            domains => {
+                let alternatives: Vec<String> =
+                    domains.iter().map(|d| format!("(?:{d})")).collect();
+                Some(Regex::new(&alternatives.join("|")).map_err(ConfigError::InvalidRegex)?)
+            }
+        };
+        let group = HostGroup {
+            name: names.join(", "),
+            ips: members.ips.into_iter().collect(),
+            macs: members.macs,
+            domains,
+        };
+        Ok(match include {
+            true => HostFilter::InGroup(group),
+            false => HostFilter::NotInGroup(group),
+        })
+    }
+}
+
+/// Parses a log level, `off` disables logging
+fn log_level_from_str(filter_level: &str) -> Result<Option<slog::Level>, ConfigError> {
+    match slog::Level::from_str(filter_level) {
+        Ok(s) => Ok(Some(s)),
+        Err(_) => {
+            if filter_level == "off" {
+                Ok(None)
+            } else {
+                Err(ConfigError::InvalidLogLevel(filter_level.to_string()))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
 struct MidFilter {
-    src: Option<Vec<IpAddr>>,
-    dst: Option<Vec<IpAddr>>,
+    src: Option<HostList>,
+    dst: Option<HostList>,
     src_regex: Option<String>,
     dst_regex: Option<String>,
-    src_exclude: Option<Vec<IpAddr>>,
-    dst_exclude: Option<Vec<IpAddr>>,
+    sni_regex: Option<String>,
+    host_regex: Option<String>,
+    path_regex: Option<String>,
+    match_expr: Option<String>,
+    src_exclude: Option<HostList>,
+    dst_exclude: Option<HostList>,
     src_mac: Option<Vec<MacAddr>>,
     dst_mac: Option<Vec<MacAddr>>,
     src_mac_exclude: Option<Vec<MacAddr>>,
     dst_mac_exclude: Option<Vec<MacAddr>>,
+    src_port: Option<Vec<PortRange>>,
+    dst_port: Option<Vec<PortRange>>,
+    proto: Option<TransportProto>,
     mode: Option<PacketAction>,
     prob: Option<f64>,
+    dry_run: Option<bool>,
+    on_unresolved: Option<UnresolvedPolicy>,
+    priority: Option<i64>,
+    enabled: Option<bool>,
+    schedule: Option<Schedule>,
+    quota: Option<Quota>,
 }
 
 impl TryFrom<&toml::Value> for MidFilter {
     type Error = ConfigError;
     fn try_from(value: &toml::Value) -> std::result::Result<Self, Self::Error> {
-        let src = value.get("src").map(ip_vec_from_value).transpose()?;
-        let dst = value.get("dst").map(ip_vec_from_value).transpose()?;
+        let src = value.get("src").map(HostList::try_from).transpose()?;
+        let dst = value.get("dst").map(HostList::try_from).transpose()?;
         let src_exclude = value
             .get("src_exclude")
-            .map(ip_vec_from_value)
+            .map(HostList::try_from)
             .transpose()?;
         let dst_exclude = value
             .get("dst_exclude")
-            .map(ip_vec_from_value)
+            .map(HostList::try_from)
             .transpose()?;
         let src_mac = value.get("src_mac").map(mac_vec_from_value).transpose()?;
         let dst_mac = value.get("dst_mac").map(mac_vec_from_value).transpose()?;
@@ -185,6 +586,18 @@ impl TryFrom<&toml::Value> for MidFilter {
             .transpose()?;
         let src_regex = value.get("src_regex").map(string_from_value).transpose()?;
         let dst_regex = value.get("dst_regex").map(string_from_value).transpose()?;
+        let sni_regex = value.get("sni_regex").map(string_from_value).transpose()?;
+        let host_regex = value.get("host_regex").map(string_from_value).transpose()?;
+        let path_regex = value.get("path_regex").map(string_from_value).transpose()?;
+        let match_expr = value.get("match").map(string_from_value).transpose()?;
+        let src_port = value.get("src_port").map(port_vec_from_value).transpose()?;
+        let dst_port = value.get("dst_port").map(port_vec_from_value).transpose()?;
+        let proto = value
+            .get("proto")
+            .map(string_from_value)
+            .transpose()?
+            .map(|s| TransportProto::try_from(s.as_str()))
+            .transpose()?;
         let mode = value
             .get("mode")
             .map(string_from_value)
@@ -197,27 +610,55 @@ impl TryFrom<&toml::Value> for MidFilter {
         // };
         // option<Result<f32, ConfigError>>
         let prob = value.get("prob").map(prob_from_value).transpose()?;
+        let dry_run = value.get("dry_run").map(bool_from_value).transpose()?;
+        let on_unresolved = value
+            .get("on_unresolved")
+            .map(policy_from_value)
+            .transpose()?;
+        let priority = value
+            .get("priority")
+            .map(|v| {
+                v.as_integer()
+                    .ok_or(ConfigError::InvalidPriority(v.clone()))
+            })
+            .transpose()?;
+        let enabled = value.get("enabled").map(bool_from_value).transpose()?;
+        let schedule = value.get("schedule").map(Schedule::try_from).transpose()?;
+        let quota = value.get("quota").map(Quota::try_from).transpose()?;
 
         Ok(Self {
             src,
             dst,
             src_regex,
             dst_regex,
+            sni_regex,
+            host_regex,
+            path_regex,
+            match_expr,
             src_exclude,
             dst_exclude,
             src_mac,
             dst_mac,
             src_mac_exclude,
             dst_mac_exclude,
+            src_port,
+            dst_port,
+            proto,
             mode,
             prob,
+            dry_run,
+            on_unresolved,
+            priority,
+            enabled,
+            schedule,
+            quota,
         })
     }
 }
 
-impl TryInto<Filter> for &MidFilter {
-    type Error = ConfigError;
-    fn try_into(self) -> std::result::Result<Filter, Self::Error> {
+impl MidFilter {
+    /// Builds the filter, looking up the groups its host lists reference
+    fn to_filter(&self, groups: &Groups) -> Result<Filter, ConfigError> {
         if self.src.is_some() && self.src_regex.is_some()
             || self.src.is_some() && self.src_exclude.is_some()
             || self.src_regex.is_some() && self.src_exclude.is_some()
@@ -231,19 +672,33 @@ impl TryInto<Filter> for &MidFilter {
             return Err(ConfigError::MultipleFiltersFound);
         }
         let mut fil = Filter {
+            rule: 0,
+            priority: self.priority.unwrap_or(0),
+            enabled: self.enabled.unwrap_or(true),
             src: HostFilter::WildCard,
             dst: HostFilter::WildCard,
+            src_port: PortFilter::WildCard,
+            dst_port: PortFilter::WildCard,
+            proto: self.proto,
+            sni: optional_regex(&self.sni_regex)?,
+            http_host: optional_regex(&self.host_regex)?,
+            http_path: optional_regex(&self.path_regex)?,
+            expr: self.match_expr.as_deref().map(expr::parse).transpose()?,
+            schedule: self.schedule.clone(),
+            quota: self.quota,
             mode: PacketAction::Reset,
             prob: 1.,
+            dry_run: self.dry_run,
+            on_unresolved: self.on_unresolved,
         };
         if let Some(l) = &self.src {
-            fil.src = HostFilter::IncludeIPs(l.to_vec())
+            fil.src = l.resolve(groups, true)?
         }
         if let Some(l) = &self.src_regex {
             fil.src = HostFilter::Regex(Regex::new(l).map_err(ConfigError::InvalidRegex)?)
         }
         if let Some(l) = &self.src_exclude {
-            fil.src = HostFilter::ExcludeIPs(l.to_vec())
+            fil.src = l.resolve(groups, false)?
         }
         if let Some(l) = &self.src_mac {
             fil.src = HostFilter::IncludeMACs(l.to_vec())
@@ -252,13 +707,13 @@ impl TryInto<Filter> for &MidFilter {
             fil.src = HostFilter::ExcludeMACs(l.to_vec())
         }
         if let Some(l) = &self.dst {
-            fil.dst = HostFilter::IncludeIPs(l.to_vec())
+            fil.dst = l.resolve(groups, true)?
         }
         if let Some(l) = &self.dst_regex {
             fil.dst = HostFilter::Regex(Regex::new(l).map_err(ConfigError::InvalidRegex)?)
         }
         if let Some(l) = &self.dst_exclude {
-            fil.dst = HostFilter::ExcludeIPs(l.to_vec())
+            fil.dst = l.resolve(groups, false)?
         }
         if let Some(l) = &self.dst_mac {
             fil.dst = HostFilter::IncludeMACs(l.to_vec())
@@ -266,26 +721,109 @@ impl TryInto<Filter> for &MidFilter {
         if let Some(l) = &self.dst_mac_exclude {
             fil.dst = HostFilter::ExcludeMACs(l.to_vec())
         }
+        if let Some(l) = &self.src_port {
+            fil.src_port = PortFilter::Ports(l.to_vec())
+        }
+        if let Some(l) = &self.dst_port {
+            fil.dst_port = PortFilter::Ports(l.to_vec())
+        }
         if let Some(m) = &self.mode {
             fil.mode = *m
         }
         if let Some(p) = &self.prob {
             fil.prob = *p;
         }
+        match (fil.mode, &fil.quota) {
+            (PacketAction::Quota, None) => {
+                return Err(ConfigError::InvalidQuota(
+                    "mode quota needs a quota table".to_string(),
+                ))
+            }
+            (PacketAction::Quota, Some(_)) | (_, None) => {}
+            (mode, Some(_)) => {
+                return Err(ConfigError::InvalidQuota(format!(
+                    "a quota table needs mode quota, not {mode:?}"
+                )))
+            }
+        }
         Ok(fil)
     }
 }
 
-fn ip_vec_from_value(item: &Value) -> Result<Vec<IpAddr>, ConfigError> {
+/// Builds a filter from a table with the keys of a `[[filter]]` one, for
+/// filters added while running
+pub fn filter_from_value(value: &Value, groups: &Groups) -> Result<Filter, ConfigError> {
+    if !value.is_table() {
+        return Err(ConfigError::FailedToParseConfig(
+            "Expected a filter table".to_string(),
+        ));
+    }
+    MidFilter::try_from(value)?.to_filter(groups)
+}
+
+/// Edits the `[[filter]]` tables of the config file in place, keeping its
+/// comments and layout. The file is replaced in one go, so it's never seen
+/// half written.
+pub fn edit_filters<T>(
+    path: &Path,
+    edit: impl FnOnce(&mut ArrayOfTables) -> Result<T, ConfigError>,
+) -> Result<T, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(ConfigError::FailedToReadConfig)?;
+    let mut doc = contents
+        .parse::<Document>()
+        .map_err(|e| ConfigError::FailedToParseConfig(e.to_string()))?;
+    let filters = doc
+        .get_mut("filter")
+        .and_then(Item::as_array_of_tables_mut)
+        .ok_or_else(|| {
+            ConfigError::FailedToParseConfig("Failed to parse filters as an array".to_string())
+        })?;
+    let out = edit(filters)?;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{name}.tmp"));
+    let write = || -> std::io::Result<()> {
+        std::fs::write(&tmp, doc.to_string())?;
+        std::fs::set_permissions(&tmp, std::fs::metadata(path)?.permissions())?;
+        std::fs::rename(&tmp, path)
+    };
+    write().map_err(ConfigError::FailedToWriteConfig)?;
+    Ok(out)
+}
+
+/// `[[filter]]` table from a filter given as a TOML value
+pub fn filter_table(value: &Value) -> Result<Table, ConfigError> {
+    let mut doc = toml::map::Map::new();
+    doc.insert("filter".to_string(), Value::Array(vec![value.clone()]));
+    let text =
+        toml::to_string(&doc).map_err(|e| ConfigError::FailedToParseConfig(e.to_string()))?;
+    let mut table = text
+        .parse::<Document>()
+        .ok()
+        .and_then(|mut doc| doc.remove("filter"))
+        .and_then(|item| item.into_array_of_tables().ok())
+        .and_then(|mut tables| tables.get_mut(0).cloned())
+        .ok_or_else(|| ConfigError::FailedToParseConfig("Invalid filter table".to_string()))?;
+    // a blank line apart from the filter before it
+    table.decor_mut().set_prefix("\n");
+    Ok(table)
+}
+
+fn optional_regex(rgx: &Option<String>) -> Result<Option<Regex>, ConfigError> {
+    rgx.as_deref()
+        .map(Regex::new)
+        .transpose()
+        .map_err(ConfigError::InvalidRegex)
+}
+
+fn ip_vec_from_value(item: &Value) -> Result<Vec<IpRange>, ConfigError> {
     let v = item.as_array().ok_or(ConfigError::ExpectedAList)?;
-    let mut vec: Vec<IpAddr> = Vec::with_capacity(v.len());
+    let mut vec: Vec<IpRange> = Vec::with_capacity(v.len());
     for i in v {
         let s = i
             .as_str()
             .ok_or(ConfigError::FailedToParseAsString(i.clone()))?;
-        let s = IpAddr::from_str(s)
-            .map_err(|e| ConfigError::FailedToParseAsIpAddr(format!("{} : {}", e, s)))?;
-        vec.push(s)
+        vec.push(IpRange::try_from(s)?)
     }
     Ok(vec)
 }
@@ -303,12 +841,36 @@ fn mac_vec_from_value(item: &Value) -> Result<Vec<MacAddr>, ConfigError> {
     Ok(vec)
 }
 
+/// Accepts a port, a `"start-end"` range or a list of either
+fn port_vec_from_value(item: &Value) -> Result<Vec<PortRange>, ConfigError> {
+    let port_from_value = |i: &Value| match i {
+        Value::Integer(p) => u16::try_from(*p)
+            .map(PortRange::from)
+            .map_err(|_| ConfigError::InvalidPort(p.to_string())),
+        Value::String(s) => PortRange::try_from(s.as_str()),
+        _ => Err(ConfigError::InvalidPort(i.to_string())),
+    };
+    match item.as_array() {
+        Some(v) => v.iter().map(port_from_value).collect(),
+        None => Ok(vec![port_from_value(item)?]),
+    }
+}
+
 fn string_from_value(item: &Value) -> Result<String, ConfigError> {
     item.as_str()
         .map(|v| v.to_string())
         .ok_or(ConfigError::FailedToParseAsString(item.clone()))
 }
 
+fn bool_from_value(item: &Value) -> Result<bool, ConfigError> {
+    item.as_bool()
+        .ok_or(ConfigError::FailedToParseAsBool(item.clone()))
+}
+
+fn policy_from_value(item: &Value) -> Result<UnresolvedPolicy, ConfigError> {
+    UnresolvedPolicy::try_from(string_from_value(item)?.as_str())
+}
+
 fn prob_from_value(item: &Value) -> Result<f64, ConfigError> {
     let i = item
         .as_float()
@@ -319,3 +881,160 @@ fn prob_from_value(item: &Value) -> Result<f64, ConfigError> {
         Ok(i)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{config_candidates, edit_filters, filter_table, find_config, Groups, HostList};
+    use crate::{errors::ConfigError, filters::HostFilter};
+    use std::{ffi::OsString, path::PathBuf};
+    use toml::Value;
+
+    fn groups(toml: &str) -> Result<Groups, ConfigError> {
+        let value = toml.parse::<Value>().unwrap();
+        Groups::parse(value.get("groups"))
+    }
+
+    fn host_filter(groups: &Groups, list: &str) -> Result<HostFilter, ConfigError> {
+        let value = format!("src = {list}").parse::<Value>().unwrap();
+        HostList::try_from(&value["src"])?.resolve(groups, true)
+    }
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<OsString> + 'a {
+        move |var| {
+            vars.iter()
+                .find(|(k, _)| *k == var)
+                .map(|(_, v)| OsString::from(v))
+        }
+    }
+
+    #[test]
+    fn candidates_follow_lookup_order() {
+        let vars = [
+            ("RESETTI_CONFIG", "/srv/resetti.toml"),
+            ("XDG_CONFIG_HOME", "/home/op/.xdg"),
+            ("HOME", "/home/op"),
+        ];
+        let expected: Vec<PathBuf> = [
+            "/srv/resetti.toml",
+            "/home/op/.xdg/resetti/config.toml",
+            "/home/op/.config/resetti/config.toml",
+            "/etc/resetti/config.toml",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(config_candidates(env(&vars)), expected);
+    }
+
+    #[test]
+    fn unset_variables_are_skipped() {
+        let expected = vec![PathBuf::from("/etc/resetti/config.toml")];
+        assert_eq!(config_candidates(env(&[])), expected);
+    }
+
+    #[test]
+    fn first_existing_candidate_wins() {
+        let dir = std::env::temp_dir().join(format!("resetti-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join(".config/resetti")).unwrap();
+        let file = dir.join(".config/resetti/config.toml");
+        std::fs::write(&file, "").unwrap();
+        let home = dir.to_str().unwrap();
+        let vars = [
+            ("RESETTI_CONFIG", "/nonexistent/resetti.toml"),
+            ("HOME", home),
+        ];
+        let found = find_config(env(&vars));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found.unwrap(), file);
+    }
+
+    #[test]
+    fn missing_config_lists_every_location_tried() {
+        let vars = [("RESETTI_CONFIG", "/nonexistent/resetti.toml")];
+        let err = find_config(env(&vars)).unwrap_err().to_string();
+        assert!(err.contains("/nonexistent/resetti.toml"));
+        assert!(err.contains("/etc/resetti/config.toml"));
+    }
+
+    #[test]
+    fn groups_include_other_groups() {
+        let groups = groups(
+            r#"
+            [groups.cameras]
+            ips = ["10.0.5.0/24"]
+            macs = ["aa:bb:cc:dd:ee:ff"]
+            [groups.iot]
+            domains = ["tuya"]
+            include = ["@cameras"]
+            "#,
+        )
+        .unwrap();
+        let HostFilter::InGroup(group) = host_filter(&groups, r#"["@iot", "10.0.9.1"]"#).unwrap()
+        else {
+            panic!("expected a group filter");
+        };
+        assert!(group.ips.contains(&"10.0.5.7".parse().unwrap()));
+        assert!(group.ips.contains(&"10.0.9.1".parse().unwrap()));
+        assert_eq!(group.macs.len(), 1);
+        assert_eq!(group.domains.unwrap().as_str(), "(?:tuya)");
+        assert!(matches!(
+            host_filter(&groups, r#"["10.0.9.1"]"#).unwrap(),
+            HostFilter::IncludeIPs(_)
+        ));
+    }
+
+    #[test]
+    fn unknown_groups_are_rejected() {
+        let err = host_filter(&Groups::default(), r#""@nope""#).unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownGroup(name) if name == "nope"));
+        let err = groups("[groups.a]\ninclude = [\"@b\"]").unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownGroup(name) if name == "b"));
+    }
+
+    #[test]
+    fn cyclic_groups_are_rejected() {
+        let err = groups(
+            r#"
+            [groups.a]
+            include = ["@b"]
+            [groups.b]
+            include = ["@a"]
+            "#,
+        )
+        .unwrap_err();
+        let ConfigError::CyclicGroup(cycle) = err else {
+            panic!("expected a cycle, got {err:?}");
+        };
+        assert!(cycle == "@a -> @b -> @a" || cycle == "@b -> @a -> @b");
+    }
+
+    #[test]
+    fn edited_filters_keep_the_comments() {
+        let path = std::env::temp_dir().join(format!("resetti-edit-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "# blocked at work\n[[filter]]\ndst_regex = \"tiktok\" # short videos\n\n[[filter]]\ndst_port = [22]\n",
+        )
+        .unwrap();
+        let rule = "dst = [\"10.0.0.5\"]\nmode = \"monitor\""
+            .parse::<Value>()
+            .unwrap();
+        let table = filter_table(&rule).unwrap();
+        let edited = edit_filters(&path, |filters| {
+            filters.remove(1);
+            filters
+                .get_mut(0)
+                .unwrap()
+                .insert("enabled", toml_edit::value(false));
+            filters.push(table);
+            Ok(filters.len())
+        });
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(edited.unwrap(), 2);
+        assert_eq!(
+            contents,
+            "# blocked at work\n[[filter]]\ndst_regex = \"tiktok\" # short videos\nenabled = false\n\n[[filter]]\ndst = [\"10.0.0.5\"]\nmode = \"monitor\"\n"
+        );
+    }
+}
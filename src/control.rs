@@ -0,0 +1,445 @@
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use slog::{info, warn};
+use slog_scope::logger;
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io::{self, BufRead, BufReader, Write},
+    net::IpAddr,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+use toml_edit::{value, ArrayOfTables, Table};
+
+use crate::{
+    configs::{edit_filters, filter_from_value, filter_table, Config, Groups},
+    domains::Lookup,
+    errors::ConfigError,
+    filters::Blacklist,
+    flows::Flow,
+    sniffer::Sniffer,
+};
+
+/// Requests of the control socket, sent as one JSON object per line like
+/// `{"cmd": "disable-rule", "id": 3}`. Rules are named by their id, their
+/// position in the config file.
+#[derive(Debug, Subcommand, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    /// List the filters in evaluation order
+    ListRules,
+    /// Add a filter, a JSON object with the keys of a `[[filter]]` table
+    AddRule {
+        /// Like `{"dst_regex": "tiktok", "mode": "reset"}`
+        #[arg(value_parser = parse_rule)]
+        rule: Value,
+        /// Append it to the config file instead, which is then reloaded
+        #[arg(long)]
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Remove a filter
+    RemoveRule {
+        /// Id from `list-rules`
+        id: usize,
+        /// Remove it from the config file instead, which is then reloaded
+        #[arg(long)]
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Turn a disabled filter back on
+    EnableRule {
+        /// Id from `list-rules`
+        id: usize,
+        /// Change the config file instead, which is then reloaded
+        #[arg(long)]
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Skip a filter until it's enabled again
+    DisableRule {
+        /// Id from `list-rules`
+        id: usize,
+        /// Change the config file instead, which is then reloaded
+        #[arg(long)]
+        #[serde(default)]
+        persist: bool,
+    },
+    /// List the tracked flows
+    Flows,
+    /// Packet, flow and DNS cache counters
+    Stats,
+    /// Name of an address, queuing a reverse lookup if it's unknown
+    Resolve { ip: IpAddr },
+}
+
+impl Request {
+    fn changes_rules(&self) -> bool {
+        matches!(
+            self,
+            Self::AddRule { .. }
+                | Self::RemoveRule { .. }
+                | Self::EnableRule { .. }
+                | Self::DisableRule { .. }
+        )
+    }
+}
+
+fn parse_rule(rule: &str) -> Result<Value, String> {
+    match serde_json::from_str(rule) {
+        Ok(rule @ Value::Object(_)) => Ok(rule),
+        Ok(_) => Err("expected a JSON object".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// A request along with where to send its response
+pub type Pending = (Request, Sender<Value>);
+
+/// Listens on the control socket, each client gets a thread. Requests are
+/// handed over to the capture loop, which answers them between packets.
+pub fn serve(path: &Path) -> io::Result<Receiver<Pending>> {
+    match fs::symlink_metadata(path) {
+        // left behind by a run that didn't shut down cleanly
+        Ok(meta) if meta.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another instance is listening on it",
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the path exists and isn't a socket",
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    // requests change what gets reset, only the owner may send them. The
+    // socket is bound in a directory only we can enter and linked into place
+    // once its mode is tightened, so no one can connect in between.
+    let mut private = path.as_os_str().to_owned();
+    private.push(format!(".{}", std::process::id()));
+    let private = PathBuf::from(private);
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("sock");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+        fs::hard_link(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&private);
+    let listener = listener?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let Ok(conn) = conn else {
+                continue;
+            };
+            let tx = tx.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_client(conn, tx) {
+                    warn!(logger(), "Control client failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(rx)
+}
+
+fn serve_client(conn: UnixStream, requests: Sender<Pending>) -> io::Result<()> {
+    let mut out = conn.try_clone()?;
+    for line in BufReader::new(conn).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let (tx, rx) = mpsc::channel();
+                if requests.send((request, tx)).is_err() {
+                    break;
+                }
+                rx.recv()
+                    .unwrap_or_else(|_| failure("shutting down".to_string()))
+            }
+            Err(e) => failure(format!("invalid request: {e}")),
+        };
+        writeln!(out, "{response}")?;
+    }
+    Ok(())
+}
+
+/// Sends one request to a running instance and waits for its response
+pub fn send(path: &Path, request: &Request) -> io::Result<Value> {
+    let mut conn = UnixStream::connect(path)?;
+    writeln!(conn, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(conn).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn failure(error: String) -> Value {
+    json!({ "ok": false, "error": error })
+}
+
+/// Applies control requests to the running sniffer
+pub struct Control {
+    config_path: PathBuf,
+    groups: Groups,
+    /// `--dry-run` was given, it holds for added filters too
+    dry_run: bool,
+    /// Raised once a change is saved, the filters are then reloaded from the
+    /// file
+    reload: Arc<AtomicBool>,
+}
+
+impl Control {
+    pub fn new(config: &Config, dry_run: bool, reload: Arc<AtomicBool>) -> Self {
+        Self {
+            config_path: config.path.clone(),
+            groups: config.groups.clone(),
+            dry_run,
+            reload,
+        }
+    }
+
+    /// Follows a reloaded config, added filters may use its new groups
+    pub fn reload(&mut self, config: &Config) {
+        self.groups = config.groups.clone();
+    }
+
+    pub fn handle(&mut self, request: Request, sniffer: &mut Sniffer) -> Value {
+        let changes = request.changes_rules().then(|| format!("{request:?}"));
+        let response = match self.apply(request, sniffer) {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(e) => failure(e.to_string()),
+        };
+        if let Some(request) = changes {
+            info!(logger(), "Control request {}: {}", request, response);
+        }
+        response
+    }
+
+    fn apply(&mut self, request: Request, sniffer: &mut Sniffer) -> Result<Value, ConfigError> {
+        match request {
+            Request::ListRules => Ok(list_rules(sniffer.blacklist())),
+            Request::AddRule { rule, persist } => self.add_rule(&rule, persist, sniffer),
+            Request::RemoveRule { id, persist: true } => self.persist(|filters| {
+                file_rule(filters, id)?;
+                filters.remove(id - 1);
+                Ok(json!({ "id": id, "persisted": true }))
+            }),
+            Request::RemoveRule { id, persist: false } => {
+                sniffer.change_filters(|b| b.remove(id))?;
+                Ok(json!({ "id": id }))
+            }
+            Request::EnableRule { id, persist } => self.set_enabled(id, true, persist, sniffer),
+            Request::DisableRule { id, persist } => self.set_enabled(id, false, persist, sniffer),
+            Request::Flows => Ok(sniffer.flows().iter().map(flow_json).collect()),
+            Request::Stats => Ok(json!({
+                "packets": sniffer.packets(),
+                "malformed": sniffer.malformed(),
+                "flows": sniffer.flows().len(),
+                "filters": sniffer.blacklist().filters().len(),
+                "dns": sniffer.dns_stats(),
+            })),
+            Request::Resolve { ip } => Ok(match sniffer.resolve(&ip) {
+                Lookup::Found(name) => json!({
+                    "status": "found",
+                    "name": name.name,
+                    "aliases": name.aliases,
+                    "source": name.source.as_str(),
+                }),
+                Lookup::Unresolvable => json!({ "status": "unresolvable" }),
+                Lookup::Pending => json!({ "status": "pending" }),
+            }),
+        }
+    }
+
+    fn add_rule(
+        &mut self,
+        rule: &Value,
+        persist: bool,
+        sniffer: &mut Sniffer,
+    ) -> Result<Value, ConfigError> {
+        let rule = toml::Value::try_from(rule)
+            .map_err(|e| ConfigError::FailedToParseConfig(e.to_string()))?;
+        // checked in full before anything is changed
+        let mut filter = filter_from_value(&rule, &self.groups)?;
+        if persist {
+            let table = filter_table(&rule)?;
+            return self.persist(|filters| {
+                filters.push(table);
+                Ok(json!({ "id": filters.len(), "persisted": true }))
+            });
+        }
+        let filters = sniffer.blacklist().filters();
+        filter.rule = filters.iter().map(|f| f.rule).max().unwrap_or(0) + 1;
+        if self.dry_run {
+            filter.dry_run = Some(true);
+        }
+        let id = filter.rule;
+        sniffer.change_filters(|b| b.add(filter));
+        Ok(json!({ "id": id }))
+    }
+
+    fn set_enabled(
+        &mut self,
+        id: usize,
+        enabled: bool,
+        persist: bool,
+        sniffer: &mut Sniffer,
+    ) -> Result<Value, ConfigError> {
+        if !persist {
+            sniffer.change_filters(|b| b.set_enabled(id, enabled))?;
+            return Ok(json!({ "id": id, "enabled": enabled }));
+        }
+        self.persist(|filters| {
+            let table = file_rule(filters, id)?;
+            if enabled {
+                table.remove("enabled");
+            } else {
+                table.insert("enabled", value(false));
+            }
+            Ok(json!({ "id": id, "enabled": enabled, "persisted": true }))
+        })
+    }
+
+    /// Changes the config file, the live filters follow once it's reloaded,
+    /// dropping the changes that weren't saved
+    fn persist(
+        &self,
+        edit: impl FnOnce(&mut ArrayOfTables) -> Result<Value, ConfigError>,
+    ) -> Result<Value, ConfigError> {
+        let out = edit_filters(&self.config_path, edit)?;
+        self.reload.store(true, Ordering::Relaxed);
+        Ok(out)
+    }
+}
+
+/// `[[filter]]` table of a rule id in the config file
+fn file_rule(filters: &mut ArrayOfTables, id: usize) -> Result<&mut Table, ConfigError> {
+    id.checked_sub(1)
+        .and_then(|idx| filters.get_mut(idx))
+        .ok_or(ConfigError::UnknownRule(id))
+}
+
+fn list_rules(blacklist: &Blacklist) -> Value {
+    blacklist
+        .filters()
+        .iter()
+        .enumerate()
+        .map(|(idx, f)| {
+            json!({
+                "id": f.rule,
+                "position": idx + 1,
+                "priority": f.priority,
+                "enabled": f.enabled,
+                "in_schedule": blacklist.in_schedule(idx),
+                "mode": format!("{:?}", f.mode),
+                "filter": format!("{f:?}"),
+            })
+        })
+        .collect()
+}
+
+fn flow_json(flow: &Flow) -> Value {
+    json!({
+        "proto": format!("{:?}", flow.proto),
+        "initiator": flow.initiator.to_string(),
+        "responder": flow.responder.to_string(),
+        "state": flow.state.map(|s| format!("{s:?}")),
+        "sni": flow.sni,
+        "http_host": flow.http.as_ref().and_then(|r| r.host.as_deref()),
+        "http_path": flow.http.as_ref().map(|r| r.path.as_str()),
+        "packets": flow.packets,
+        "bytes": flow.bytes,
+        "first_seen": flow.first_seen.as_secs(),
+        "last_seen": flow.last_seen.as_secs(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rule, send, serve, PermissionsExt, Request, UnixListener};
+    use serde_json::json;
+
+    #[test]
+    fn requests_are_tagged_json_lines() {
+        let request: Request = serde_json::from_str(r#"{"cmd": "disable-rule", "id": 3}"#).unwrap();
+        assert!(matches!(
+            request,
+            Request::DisableRule {
+                id: 3,
+                persist: false
+            }
+        ));
+        let request: Request = serde_json::from_str(
+            r#"{"cmd": "add-rule", "rule": {"dst_regex": "tiktok"}, "persist": true}"#,
+        )
+        .unwrap();
+        assert!(matches!(request, Request::AddRule { persist: true, .. }));
+        assert_eq!(
+            serde_json::to_value(Request::Resolve {
+                ip: "10.0.0.1".parse().unwrap()
+            })
+            .unwrap(),
+            json!({ "cmd": "resolve", "ip": "10.0.0.1" })
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"cmd": "reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn rules_are_json_objects() {
+        assert!(parse_rule(r#"{"dst_port": [443], "mode": "monitor"}"#).is_ok());
+        assert!(parse_rule("[443]").is_err());
+        assert!(parse_rule("dst_port = 443").is_err());
+    }
+
+    #[test]
+    fn socket_requests_reach_the_capture_loop() {
+        let path = std::env::temp_dir().join(format!("resetti-ctl-{}.sock", std::process::id()));
+        let requests = serve(&path).unwrap();
+        let capture_loop = std::thread::spawn(move || {
+            let (request, reply) = requests.recv().unwrap();
+            reply.send(json!({ "ok": true, "result": format!("{request:?}") }))
+        });
+        let response = send(&path, &Request::Stats).unwrap();
+        capture_loop.join().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(response, json!({ "ok": true, "result": "Stats" }));
+    }
+
+    #[test]
+    fn only_stale_sockets_are_replaced() {
+        let path = std::env::temp_dir().join(format!("resetti-ctl-{}.file", std::process::id()));
+        std::fs::write(&path, "keep").unwrap();
+        assert!(serve(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
+        std::fs::remove_file(&path).unwrap();
+
+        let path = std::env::temp_dir().join(format!("resetti-ctl-{}.stale", std::process::id()));
+        drop(UnixListener::bind(&path).unwrap());
+        serve(&path).unwrap();
+        assert!(serve(&path).is_err());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -1,52 +1,624 @@
 use anyhow::Result;
 use dns_lookup::lookup_addr;
+use serde::Serialize;
 use simple_dns::rdata::RData;
-use slog::{error, info};
+use slog::{debug, error, info};
 use slog_scope::logger;
 use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::time::Duration;
 
 use crate::errors::DomainError;
+use crate::lru::LruCache;
+use crate::resolver::ResolverPool;
 
+/// Names learned from DNS are kept at least this long, even with a lower TTL,
+/// since the connection usually follows the answer right away
+const MIN_DNS_TTL: Duration = Duration::from_secs(10);
+/// PTR records aren't seen on the wire, so their TTL is unknown
+const RDNS_TTL: Duration = Duration::from_secs(3600);
+/// Failed reverse lookups aren't retried for this long
+const NEGATIVE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameSource {
+    /// Passively learned from a DNS answer
+    Dns,
+    /// Reverse resolved
+    ReverseDns,
+    /// Read from a hosts file
+    Hosts,
+}
+
+impl NameSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::ReverseDns => "rdns",
+            Self::Hosts => "hosts",
+        }
+    }
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "dns" => Some(Self::Dns),
+            "rdns" => Some(Self::ReverseDns),
+            "hosts" => Some(Self::Hosts),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    /// The name that was queried
+    pub name: String,
+    /// CNAME chain from `name` to the record holding the address, in order
+    pub aliases: Vec<String>,
+    pub source: NameSource,
+}
+
+impl Name {
+    /// `name` followed by its aliases
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_str()).chain(self.aliases.iter().map(String::as_str))
+    }
+}
+
+/// What the cache knows about an address
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup {
+    Found(Name),
+    Unresolvable,
+    /// A reverse lookup is running, ask again later
+    Pending,
+}
+
+enum Cached {
+    Name(Name),
+    /// A reverse lookup failed recently
+    Unresolvable,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub negative_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub in_flight: usize,
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} entries, {} hits, {} negative hits, {} misses, {} evictions, {} lookups in flight",
+            self.entries,
+            self.hits,
+            self.negative_hits,
+            self.misses,
+            self.evictions,
+            self.in_flight
+        )
+    }
+}
+
+/// IP to domain name cache, bounded and honoring record TTLs. Misses are
+/// reverse resolved in the background, the packet path never blocks.
 pub struct Resolved {
-    resolved: HashMap<IpAddr, String>,
+    cache: LruCache<IpAddr, Cached>,
+    /// Names from hosts files, never evicted
+    hosts: HashMap<IpAddr, Name>,
+    resolver: ResolverPool,
+    stats: CacheStats,
+    /// Names loaded from the state file with their remaining TTL, waiting
+    /// for a capture timestamp to expire on
+    restored: Vec<(IpAddr, Name, Duration)>,
+    /// Wall clock time they were loaded at
+    restored_at: Duration,
 }
 
 impl Resolved {
-    pub fn build() -> Self {
+    pub fn build(capacity: usize, resolver_threads: usize) -> Self {
         Self {
-            resolved: HashMap::new(),
-        }
-    }
-    pub fn get(&self, ip: &IpAddr) -> Option<String> {
-        self.resolved.get(ip).cloned()
-    }
-    pub fn update_from_dns(&mut self, packet: &[u8]) {
-        let answer = simple_dns::Packet::parse(&packet[42..]);
-        if let Ok(answer) = answer {
-            for i in &answer.answers {
-                let ip: Option<IpAddr> = match &i.rdata {
-                    RData::A(a) => Some(IpAddr::V4(Ipv4Addr::from(a.address))),
-                    RData::AAAA(aaaa) => Some(IpAddr::V6(Ipv6Addr::from(aaaa.address))),
-                    _ => None,
-                };
-                if ip.is_none() {
-                    continue;
+            cache: LruCache::new(capacity),
+            hosts: HashMap::new(),
+            resolver: ResolverPool::spawn(resolver_threads, resolve),
+            stats: CacheStats::default(),
+            restored: Vec::new(),
+            restored_at: Duration::ZERO,
+        }
+    }
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.cache.len(),
+            in_flight: self.resolver.in_flight(),
+            ..self.stats
+        }
+    }
+    /// Name of an address from the cache or the hosts files, a miss queues a
+    /// reverse lookup
+    pub fn name(&mut self, ip: &IpAddr, now: Duration) -> Lookup {
+        match self.cache.get(ip, now) {
+            Some(Cached::Name(name)) => {
+                self.stats.hits += 1;
+                Lookup::Found(name.clone())
+            }
+            Some(Cached::Unresolvable) => {
+                self.stats.negative_hits += 1;
+                Lookup::Unresolvable
+            }
+            None => match self.hosts.get(ip) {
+                Some(name) => {
+                    self.stats.hits += 1;
+                    Lookup::Found(name.clone())
+                }
+                None => {
+                    self.stats.misses += 1;
+                    self.resolver.request(*ip);
+                    Lookup::Pending
+                }
+            },
+        }
+    }
+    /// Name of an address if it's already known, without counting a lookup
+    /// or queuing a reverse one
+    pub fn known_name(&mut self, ip: &IpAddr, now: Duration) -> Option<Name> {
+        match self.cache.get(ip, now) {
+            Some(Cached::Name(name)) => Some(name.clone()),
+            Some(Cached::Unresolvable) => None,
+            None => self.hosts.get(ip).cloned(),
+        }
+    }
+    /// Stores the reverse lookups finished since the last call
+    pub fn collect_resolved(&mut self, now: Duration) {
+        for (ip, res) in self.resolver.finished() {
+            match res {
+                Ok(name) => {
+                    debug!(logger(), "Reverse resolved IP:[{}] Domain:[{}]", ip, name);
+                    let name = Name {
+                        name,
+                        aliases: Vec::new(),
+                        source: NameSource::ReverseDns,
+                    };
+                    self.insert(ip, Cached::Name(name), now + RDNS_TTL);
+                }
+                Err(e) => {
+                    debug!(logger(), "{}", e);
+                    self.insert(ip, Cached::Unresolvable, now + NEGATIVE_TTL);
                 }
-                let ip = ip.unwrap();
-                info!(
-                    logger(),
-                    "Extracted from DNS packet IP:[{}] Domain:[{}]", ip, i.name
+            }
+        }
+    }
+    /// Learns IP to domain mappings from the answers of a DNS response,
+    /// following CNAME records so addresses are also known by the name that
+    /// was queried. `payload` is the UDP payload of the response
+    pub fn update_from_dns(&mut self, payload: &[u8], now: Duration) {
+        let Ok(answer) = simple_dns::Packet::parse(payload) else {
+            error!(logger(), "Failed to parse DNS packet");
+            return;
+        };
+        // CNAME targets, lowercased, pointing back at the name they're an alias of
+        let mut aliased_by: HashMap<String, (String, u32)> = HashMap::new();
+        for i in &answer.answers {
+            if let RData::CNAME(cname) = &i.rdata {
+                aliased_by.insert(
+                    cname.0.to_string().to_lowercase(),
+                    (i.name.to_string(), i.ttl),
                 );
-                self.resolved.insert(ip, i.name.to_string());
-                dbg!(&self.resolved);
             }
+        }
+        for i in &answer.answers {
+            let ip: Option<IpAddr> = match &i.rdata {
+                RData::A(a) => Some(IpAddr::V4(Ipv4Addr::from(a.address))),
+                RData::AAAA(aaaa) => Some(IpAddr::V6(Ipv6Addr::from(aaaa.address))),
+                _ => None,
+            };
+            let Some(ip) = ip else {
+                continue;
+            };
+            let (mut chain, ttl) = cname_chain(&aliased_by, i.name.to_string(), i.ttl);
+            let name = chain.remove(0);
+            info!(
+                logger(),
+                "Extracted from DNS packet IP:[{}] Domain:[{}] Aliases:{:?} TTL:[{}]",
+                ip,
+                name,
+                chain,
+                ttl
+            );
+            let ttl = Duration::from_secs(ttl.into()).max(MIN_DNS_TTL);
+            let name = Name {
+                name,
+                aliases: chain,
+                source: NameSource::Dns,
+            };
+            self.insert(ip, Cached::Name(name), now + ttl);
+        }
+    }
+    /// Learns from every complete message in the reassembled bytes of a DNS
+    /// over TCP stream, each prefixed by its 2 byte length. Consumed bytes are
+    /// drained, a partial message is left for the next segments
+    pub fn update_from_dns_stream(&mut self, stream: &mut Vec<u8>, now: Duration) {
+        let mut consumed = 0;
+        while let Some(len) = stream.get(consumed..consumed + 2) {
+            let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+            let Some(message) = stream.get(consumed + 2..consumed + 2 + len) else {
+                break;
+            };
+            self.update_from_dns(message, now);
+            consumed += 2 + len;
+        }
+        stream.drain(..consumed);
+    }
+    /// Writes the learned names to `path`, one per line as
+    /// `<remaining ttl, seconds> <source> <ip> <name> [aliases...]`, after a
+    /// `saved <unix seconds>` line with the wall clock time of the save.
+    /// TTLs count from `now`, the capture time the cache expires on, so the
+    /// file doesn't depend on the clock of the capture.
+    /// Failed lookups and hosts file names aren't saved.
+    pub fn save_state(
+        &self,
+        path: &Path,
+        now: Duration,
+        wall_clock: Duration,
+    ) -> Result<usize, DomainError> {
+        let mut out = format!("# resetti learned names\nsaved {}\n", wall_clock.as_secs());
+        let mut saved = 0;
+        let cached = self
+            .cache
+            .iter()
+            .filter_map(|(ip, entry, expires)| match entry {
+                Cached::Name(name) => Some((ip, name, expires.saturating_sub(now))),
+                Cached::Unresolvable => None,
+            });
+        // names restored before any packet was seen still count from their load
+        let since_restore = wall_clock.saturating_sub(self.restored_at);
+        let restored = self
+            .restored
+            .iter()
+            .map(|(ip, name, ttl)| (ip, name, ttl.saturating_sub(since_restore)));
+        for (ip, name, ttl) in cached.chain(restored) {
+            if ttl.as_secs() == 0 {
+                continue;
+            }
+            let names: Vec<&str> = name.names().collect();
+            out.push_str(&format!(
+                "{} {} {} {}\n",
+                ttl.as_secs(),
+                name.source.as_str(),
+                ip,
+                names.join(" ")
+            ));
+            saved += 1;
+        }
+        // written aside and renamed, a crash never leaves half a file
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, out)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| DomainError::StateFile(path.to_path_buf(), e))?;
+        Ok(saved)
+    }
+    /// Loads the names saved by `save_state`, skipping the ones that expired
+    /// since, by the wall clock. They enter the cache with the first capture
+    /// timestamp, see `restore`.
+    /// A missing file is not an error, there's nothing to load on a first run
+    pub fn load_state(&mut self, path: &Path, wall_clock: Duration) -> Result<usize, DomainError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(DomainError::StateFile(path.to_path_buf(), e)),
+        };
+        let mut elapsed = Duration::ZERO;
+        let mut loaded = 0;
+        for line in contents.lines().filter(|l| !l.starts_with('#')) {
+            if let Some(saved) = line.strip_prefix("saved ") {
+                if let Ok(saved) = saved.trim().parse::<u64>() {
+                    elapsed = wall_clock.saturating_sub(Duration::from_secs(saved));
+                }
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let ttl = fields.next().and_then(|e| e.parse::<u64>().ok());
+            let source = fields.next().and_then(NameSource::from_str);
+            let ip = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+            let name = fields.next();
+            let (Some(ttl), Some(source), Some(ip), Some(name)) = (ttl, source, ip, name) else {
+                debug!(logger(), "Skipping invalid state line [{}]", line);
+                continue;
+            };
+            let ttl = Duration::from_secs(ttl).saturating_sub(elapsed);
+            if ttl.is_zero() {
+                continue;
+            }
+            let name = Name {
+                name: name.to_string(),
+                aliases: fields.map(str::to_string).collect(),
+                source,
+            };
+            self.restored.push((ip, name, ttl));
+            loaded += 1;
+        }
+        self.restored_at = wall_clock;
+        Ok(loaded)
+    }
+    /// Puts the names loaded by `load_state` in the cache, expiring their
+    /// remaining TTL after `now`
+    pub fn restore(&mut self, now: Duration) {
+        for (ip, name, ttl) in std::mem::take(&mut self.restored) {
+            self.insert(ip, Cached::Name(name), now + ttl);
+        }
+    }
+    /// Adds the names of an `/etc/hosts` style file, the first name of a line
+    /// is the main one and the others its aliases
+    pub fn load_hosts(&mut self, path: &Path) -> Result<usize, DomainError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| DomainError::HostsFile(path.to_path_buf(), e))?;
+        let mut loaded = 0;
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let ip = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+            let (Some(ip), Some(name)) = (ip, fields.next()) else {
+                continue;
+            };
+            let name = Name {
+                name: name.to_string(),
+                aliases: fields.map(str::to_string).collect(),
+                source: NameSource::Hosts,
+            };
+            self.hosts.insert(ip, name);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+    fn insert(&mut self, ip: IpAddr, entry: Cached, expires: Duration) {
+        if self.cache.insert(ip, entry, expires) {
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+/// Walks the CNAME records back from `name` to the name that was queried,
+/// returns the chain starting with the queried name and the lowest TTL on it
+fn cname_chain(
+    aliased_by: &HashMap<String, (String, u32)>,
+    name: String,
+    ttl: u32,
+) -> (Vec<String>, u32) {
+    let mut chain = vec![name];
+    let mut ttl = ttl;
+    while let Some((alias, alias_ttl)) =
+        chain.last().and_then(|n| aliased_by.get(&n.to_lowercase()))
+    {
+        // a looping chain would never reach the queried name
+        if chain.iter().any(|n| n.eq_ignore_ascii_case(alias)) {
+            break;
+        }
+        chain.push(alias.clone());
+        ttl = ttl.min(*alias_ttl);
+    }
+    chain.reverse();
+    (chain, ttl)
+}
+
+/// Blocking reverse lookup, only ever called from the resolver threads
+fn resolve(ip: &IpAddr) -> Result<String, DomainError> {
+    lookup_addr(ip).map_err(|_| DomainError::FailedToResolve(*ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cname_chain, Lookup, NameSource, Resolved};
+    use std::{collections::HashMap, net::IpAddr, path::PathBuf, time::Duration};
+
+    /// DNS response for example.com with one A record 93.184.216.34
+    fn response(ttl: u32) -> Vec<u8> {
+        let mut p = vec![
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let qname = [
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+        ];
+        p.extend_from_slice(&qname);
+        p.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        p.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        p.extend_from_slice(&ttl.to_be_bytes());
+        p.extend_from_slice(&[0x00, 0x04, 93, 184, 216, 34]);
+        p
+    }
+
+    #[test]
+    fn learned_names_follow_the_record_ttl() {
+        let ip: IpAddr = "93.184.216.34".parse().unwrap();
+        let mut rd = Resolved::build(16, 1);
+        rd.update_from_dns(&response(120), Duration::ZERO);
+        let name = rd.cache.get(&ip, Duration::from_secs(119));
+        assert!(matches!(name, Some(super::Cached::Name(n))
+            if n.name == "example.com" && n.source == NameSource::Dns));
+        assert!(rd.cache.get(&ip, Duration::from_secs(120)).is_none());
+    }
+
+    #[test]
+    fn short_ttls_are_raised_to_the_minimum() {
+        let ip: IpAddr = "93.184.216.34".parse().unwrap();
+        let mut rd = Resolved::build(16, 1);
+        rd.update_from_dns(&response(0), Duration::ZERO);
+        assert!(rd.cache.get(&ip, Duration::from_secs(9)).is_some());
+    }
+
+    /// Encodes a name as uncompressed labels
+    fn labels(name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        for label in name.split('.') {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+        out
+    }
+
+    /// www.youtube.com -> youtube-ui.l.google.com -> 142.250.74.206, with
+    /// the records in the given order
+    fn cname_response(records_reversed: bool) -> Vec<u8> {
+        let mut p = vec![
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+        ];
+        p.extend(labels("www.youtube.com"));
+        p.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        let mut cname = labels("www.youtube.com");
+        cname.extend_from_slice(&[0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c]);
+        let target = labels("youtube-ui.l.google.com");
+        cname.extend_from_slice(&(target.len() as u16).to_be_bytes());
+        cname.extend(&target);
+        let mut a = target;
+        a.extend_from_slice(&[0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c]);
+        a.extend_from_slice(&[0x00, 0x04, 142, 250, 74, 206]);
+        if records_reversed {
+            p.extend(a);
+            p.extend(cname);
         } else {
-            error!(logger(), "Failed to parse DNS packet");
+            p.extend(cname);
+            p.extend(a);
+        }
+        p
+    }
+
+    #[test]
+    fn addresses_behind_a_cname_get_the_queried_name() {
+        let ip: IpAddr = "142.250.74.206".parse().unwrap();
+        for reversed in [false, true] {
+            let mut rd = Resolved::build(16, 1);
+            rd.update_from_dns(&cname_response(reversed), Duration::ZERO);
+            let Lookup::Found(name) = rd.name(&ip, Duration::ZERO) else {
+                panic!("no name learned for {ip}");
+            };
+            assert_eq!(
+                name.names().collect::<Vec<_>>(),
+                ["www.youtube.com", "youtube-ui.l.google.com"]
+            );
+            // the lowest TTL of the chain, the A record's 60s
+            assert!(rd.cache.get(&ip, Duration::from_secs(60)).is_none());
         }
     }
-    pub fn resolve(&self, ip: &IpAddr) -> Result<String, DomainError> {
-        // println!("resolving {ip:?} to {:?}",lookup_addr(ip));
-        lookup_addr(ip).map_err(|_| DomainError::FailedToResolve(*ip))
+
+    #[test]
+    fn cname_loops_terminate() {
+        let mut aliased_by = HashMap::new();
+        aliased_by.insert("a.example".to_string(), ("b.example".to_string(), 30));
+        aliased_by.insert("b.example".to_string(), ("a.example".to_string(), 30));
+        let (chain, ttl) = cname_chain(&aliased_by, "a.example".to_string(), 60);
+        assert_eq!(chain, ["b.example", "a.example"]);
+        assert_eq!(ttl, 30);
+    }
+
+    #[test]
+    fn tcp_messages_split_across_segments_are_parsed() {
+        let ip: IpAddr = "93.184.216.34".parse().unwrap();
+        let message = response(120);
+        let mut stream = (message.len() as u16).to_be_bytes().to_vec();
+        stream.extend_from_slice(&message);
+        stream.extend_from_slice(&[0x00]);
+        let tail = stream.split_off(20);
+
+        let mut rd = Resolved::build(16, 1);
+        rd.update_from_dns_stream(&mut stream, Duration::ZERO);
+        assert_eq!(stream.len(), 20);
+        assert!(rd.cache.get(&ip, Duration::ZERO).is_none());
+
+        stream.extend(tail);
+        rd.update_from_dns_stream(&mut stream, Duration::ZERO);
+        // only the first byte of the next length prefix is left
+        assert_eq!(stream, [0x00]);
+        assert!(rd.cache.get(&ip, Duration::ZERO).is_some());
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("resetti-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn saved_state_is_reloaded_without_expired_names() {
+        let path = temp_path("state.tmp");
+        let ip: IpAddr = "142.250.74.206".parse().unwrap();
+        let mut rd = Resolved::build(16, 1);
+        // capture timestamps, unrelated to the wall clock
+        rd.update_from_dns(&cname_response(false), Duration::from_secs(1000));
+        rd.update_from_dns(&response(30), Duration::from_secs(1000));
+        let saved_at = Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            rd.save_state(&path, Duration::from_secs(1010), saved_at)
+                .unwrap(),
+            2
+        );
+
+        // saved with 20s left for the example.com record and 50s for the A
+        // record, loaded 30s later
+        let mut reloaded = Resolved::build(16, 1);
+        assert_eq!(
+            reloaded
+                .load_state(&path, saved_at + Duration::from_secs(30))
+                .unwrap(),
+            1
+        );
+        std::fs::remove_file(&path).unwrap();
+        // a replayed capture from long ago
+        let now = Duration::from_secs(500);
+        reloaded.restore(now);
+        let Lookup::Found(name) = reloaded.name(&ip, now + Duration::from_secs(19)) else {
+            panic!("name not reloaded");
+        };
+        assert_eq!(name.aliases, ["youtube-ui.l.google.com"]);
+        assert_eq!(name.source, NameSource::Dns);
+        assert!(matches!(
+            reloaded.name(&ip, now + Duration::from_secs(21)),
+            Lookup::Pending
+        ));
+    }
+
+    #[test]
+    fn missing_state_file_loads_nothing() {
+        let mut rd = Resolved::build(16, 1);
+        assert_eq!(
+            rd.load_state(&temp_path("missing"), Duration::ZERO)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn hosts_files_seed_names() {
+        let path = temp_path("hosts");
+        std::fs::write(
+            &path,
+            "# comment\n127.0.0.1 localhost\n10.0.0.5  nas.lan nas # storage\nbogus line\n",
+        )
+        .unwrap();
+        let mut rd = Resolved::build(16, 1);
+        assert_eq!(rd.load_hosts(&path).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+        let ip: IpAddr = "10.0.0.5".parse().unwrap();
+        let Lookup::Found(name) = rd.name(&ip, Duration::ZERO) else {
+            panic!("hosts name not found");
+        };
+        assert_eq!(name.names().collect::<Vec<_>>(), ["nas.lan", "nas"]);
+        assert_eq!(name.source, NameSource::Hosts);
+    }
+
+    #[test]
+    fn cached_names_count_as_hits() {
+        let ip: IpAddr = "93.184.216.34".parse().unwrap();
+        let mut rd = Resolved::build(16, 1);
+        rd.update_from_dns(&response(120), Duration::ZERO);
+        assert!(
+            matches!(rd.name(&ip, Duration::ZERO), Lookup::Found(n) if n.name == "example.com")
+        );
+        let stats = rd.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 0, 1));
     }
 }
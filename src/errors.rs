@@ -1,14 +1,18 @@
 #![allow(dead_code)]
-use std::{io, net::IpAddr};
+use std::{io, net::IpAddr, path::PathBuf};
 use thiserror::Error;
 use toml::Value;
 
 #[derive(Debug, Error)]
 pub enum ConfigError {
-    #[error("Failed to find config file")]
-    NoConfigFound,
+    #[error("Failed to find config file, tried [{}]", display_paths(.0))]
+    NoConfigFound(Vec<PathBuf>),
     #[error("No filters found")]
     NoFiltersFound,
+    #[error("Failed to write config file: {}", .0)]
+    FailedToWriteConfig(io::Error),
+    #[error("No rule with id {}", .0)]
+    UnknownRule(usize),
     #[error("Failed to read config file")]
     FailedToReadConfig(#[from] io::Error),
     #[error("Failed to parse config: {}",.0)]
@@ -19,22 +23,75 @@ pub enum ConfigError {
     ExpectedAList,
     #[error("Invalid Regex value found")]
     InvalidRegex(#[from] regex::Error),
-    #[error("Invalid mode {}, valid modes are reset|syn_reset|monitor|ignore", .0)]
+    #[error("Invalid mode {}, valid modes are reset|syn_reset|monitor|ignore|quota", .0)]
     UnknownMode(String),
     #[error("Failed to parse value as an IP address [{}]", .0)]
     FailedToParseAsIpAddr(String),
     #[error("Failed to parse value as String: {}",.0)]
     FailedToParseAsString(Value),
+    #[error("Failed to parse value as a boolean: {}",.0)]
+    FailedToParseAsBool(Value),
     #[error("Invalid log level provided : {}",.0)]
     InvalidLogLevel(String),
     #[error("Invalid value for prob : {}", .0)]
     InvalidProbValue(Value),
+    #[error("Invalid DNS cache size : {}, expected a positive integer", .0)]
+    InvalidCacheSize(Value),
+    #[error("Invalid maximum number of flows : {}, expected a positive integer", .0)]
+    InvalidMaxFlows(Value),
     #[error("Invalid MAC address")]
     InvalidMacAddr(String),
+    #[error("Invalid port or port range [{}]", .0)]
+    InvalidPort(String),
+    #[error("Invalid proto {}, valid protos are tcp|udp", .0)]
+    UnknownProto(String),
+    #[error("Invalid on_unresolved policy {}, valid policies are monitor|ignore|reset", .0)]
+    UnknownPolicy(String),
+    #[error("Invalid number of resolver threads : {}, expected a positive integer", .0)]
+    InvalidResolverThreads(Value),
+    #[error("Group {} should be a table of ips, macs, domains and include lists", .0)]
+    InvalidGroup(String),
+    #[error("Unknown group @{}", .0)]
+    UnknownGroup(String),
+    #[error("Groups include each other in a cycle: {}", .0)]
+    CyclicGroup(String),
+    #[error("Invalid schedule: {}", .0)]
+    InvalidSchedule(String),
+    #[error("Invalid quota: {}", .0)]
+    InvalidQuota(String),
+    #[error("Invalid priority : {}, expected an integer", .0)]
+    InvalidPriority(Value),
+    #[error("Invalid match expression at column {}: {}\n  {}\n  {}^", .column, .reason, .expr, " ".repeat(.column.saturating_sub(1)))]
+    InvalidExpression {
+        expr: String,
+        column: usize,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PacketError {
+    #[error("Frame truncated inside the {} header", .0)]
+    Truncated(&'static str),
+    #[error("Invalid {} header length", .0)]
+    InvalidHeaderLength(&'static str),
+    #[error("Unsupported ether type {:#06x}", .0)]
+    UnsupportedEtherType(u16),
+    #[error("IP version {} doesn't match the ether type", .0)]
+    IpVersionMismatch(u8),
 }
 
 #[derive(Debug, Error)]
 pub enum DomainError {
     #[error("Failed to resolve addr: {0}")]
     FailedToResolve(IpAddr),
+    #[error("Failed to read or write state file {}: {}", .0.display(), .1)]
+    StateFile(PathBuf, io::Error),
+    #[error("Failed to read hosts file {}: {}", .0.display(), .1)]
+    HostsFile(PathBuf, io::Error),
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    paths.join(", ")
 }
@@ -0,0 +1,170 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use slog::warn;
+use slog_scope::logger;
+use std::{
+    fs::OpenOptions,
+    io::{self, LineWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+use crate::{packet_utils::ParsedPacket, sniffer::Decision};
+
+/// JSON Lines log of the monitored and reset connections, one object per
+/// event, meant for other tools rather than people
+pub struct EventLog {
+    out: Box<dyn Write>,
+    /// Name of the sniffed interface, `None` when replaying a capture
+    interface: Option<String>,
+}
+
+impl EventLog {
+    /// Appends to the file at `path`, or writes to stdout if it's `-`
+    pub fn open(path: &Path, interface: Option<String>) -> io::Result<Self> {
+        let out: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Box::new(LineWriter::new(file))
+        };
+        Ok(Self { out, interface })
+    }
+
+    /// Writes the event of a decision taken at `ts`, `sent` tells whether its
+    /// RST made it onto the wire
+    pub fn record(&mut self, decision: &Decision, ts: Duration, sent: bool) {
+        let event = event(decision, ts, self.interface.as_deref(), sent);
+        if let Err(e) = writeln!(self.out, "{event}") {
+            warn!(logger(), "Failed to write event: {}", e);
+        }
+    }
+}
+
+fn event(decision: &Decision, ts: Duration, interface: Option<&str>, sent: bool) -> Value {
+    let timestamp = DateTime::<Utc>::from_timestamp(ts.as_secs() as i64, ts.subsec_nanos())
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Micros, true);
+    let (src_port, dst_port) = decision.ports.unzip();
+    let (domain, domain_source) = decision.domain.clone().unzip();
+    let rst = decision
+        .rst
+        .as_deref()
+        .and_then(|rst| ParsedPacket::parse(rst).ok())
+        .and_then(|rst| {
+            rst.tcp()
+                .map(|tcp| json!({"seq": tcp.seq_num, "ack": tcp.ack_num, "sent": sent}))
+        });
+    json!({
+        "timestamp": timestamp,
+        "interface": interface,
+        "proto": decision.proto.map(|p| p.as_str()),
+        "src": decision.src,
+        "src_port": src_port,
+        "dst": decision.dst,
+        "dst_port": dst_port,
+        "src_mac": decision.src_mac.as_ref().map(mac),
+        "dst_mac": decision.dst_mac.as_ref().map(mac),
+        "domain": domain,
+        "domain_source": domain_source,
+        "rule": decision.rule,
+        "action": decision.action.as_str(),
+        "unresolved": decision.unresolved,
+        "dry_run": decision.dry_run,
+        "rst": rst,
+    })
+}
+
+fn mac(addr: &[u8; 6]) -> String {
+    addr.iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::event;
+    use crate::{
+        filters::{PacketAction, TransportProto},
+        sniffer::Decision,
+    };
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn event_of_a_monitored_flow() {
+        let decision = Decision {
+            src: "192.168.0.103".parse().unwrap(),
+            dst: "93.184.216.34".parse().unwrap(),
+            ports: Some((51000, 443)),
+            proto: Some(TransportProto::Tcp),
+            src_mac: Some([0x02, 0x42, 0xac, 0x11, 0x00, 0x02]),
+            dst_mac: None,
+            domain: Some(("example.com".to_string(), "sni")),
+            filter: Some(0),
+            rule: Some(4),
+            action: PacketAction::Monitor,
+            unresolved: false,
+            first: true,
+            rst: None,
+            dry_run: false,
+        };
+        assert!(decision.is_event());
+        let event = event(
+            &decision,
+            Duration::new(1_760_000_000, 250_000_000),
+            Some("eth0"),
+            false,
+        );
+        assert_eq!(
+            event,
+            json!({
+                "timestamp": "2025-10-09T08:53:20.250000Z",
+                "interface": "eth0",
+                "proto": "tcp",
+                "src": "192.168.0.103",
+                "src_port": 51000,
+                "dst": "93.184.216.34",
+                "dst_port": 443,
+                "src_mac": "02:42:ac:11:00:02",
+                "dst_mac": null,
+                "domain": "example.com",
+                "domain_source": "sni",
+                "rule": 4,
+                "action": "monitor",
+                "unresolved": false,
+                "dry_run": false,
+                "rst": null,
+            })
+        );
+    }
+
+    #[test]
+    fn udp_reset_match_is_logged_without_rst() {
+        let mut decision = Decision {
+            src: "192.168.0.103".parse().unwrap(),
+            dst: "8.8.8.8".parse().unwrap(),
+            ports: Some((51000, 443)),
+            proto: Some(TransportProto::Udp),
+            src_mac: None,
+            dst_mac: None,
+            domain: None,
+            filter: Some(0),
+            rule: Some(1),
+            action: PacketAction::Reset,
+            unresolved: false,
+            first: true,
+            rst: None,
+            dry_run: false,
+        };
+        assert!(decision.is_event());
+        let event = event(&decision, Duration::ZERO, None, false);
+        assert_eq!(event["action"], "reset");
+        assert_eq!(event["proto"], "udp");
+        assert_eq!(event["rst"], json!(null));
+        // later packets of the flow take the cached match
+        decision.first = false;
+        assert!(!decision.is_event());
+    }
+}
@@ -0,0 +1,423 @@
+use regex::Regex;
+use std::{net::IpAddr, time::Duration};
+
+use crate::{
+    domains::Resolved,
+    errors::ConfigError,
+    filters::{name_matches, Endpoint, MacAddr, PacketInfo, PortRange, TransportProto},
+    ipset::{IpRange, IpSet},
+};
+
+/// Boolean filter expression from the `match` key of a filter, e.g.
+/// `(src ip 10.0.0.5 or src mac aa:bb:cc:dd:ee:ff) and not dst domain ~ "\.corp$"`
+#[derive(Debug, Clone)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Test(Side, Predicate),
+}
+
+/// Which end of the packet a test looks at, `Either` passes if one of them does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Src,
+    Dst,
+    Either,
+}
+
+#[derive(Debug, Clone)]
+pub enum Predicate {
+    /// `ip` and `net`, an address is a one address set
+    Ip(IpSet),
+    Mac(MacAddr),
+    Port(PortRange),
+    Proto(TransportProto),
+    Domain(Regex),
+    Sni(Regex),
+    Host(Regex),
+    Path(Regex),
+}
+
+impl Expr {
+    /// Whether a packet matches, `None` if that depends on a name still being
+    /// resolved
+    pub fn eval(&self, info: &PacketInfo, rd: &mut Resolved, now: Duration) -> Option<bool> {
+        match self {
+            Self::And(a, b) => and(a.eval(info, rd, now), || b.eval(info, rd, now)),
+            Self::Or(a, b) => or(a.eval(info, rd, now), || b.eval(info, rd, now)),
+            Self::Not(e) => e.eval(info, rd, now).map(|v| !v),
+            Self::Test(Side::Src, p) => p.eval(info, &info.src, rd, now),
+            Self::Test(Side::Dst, p) => p.eval(info, &info.dst, rd, now),
+            Self::Test(Side::Either, p) => or(p.eval(info, &info.src, rd, now), || {
+                p.eval(info, &info.dst, rd, now)
+            }),
+        }
+    }
+}
+
+impl Predicate {
+    fn eval(
+        &self,
+        info: &PacketInfo,
+        end: &Endpoint,
+        rd: &mut Resolved,
+        now: Duration,
+    ) -> Option<bool> {
+        let matches = |rgx: &Regex, value: Option<&str>| value.is_some_and(|v| rgx.is_match(v));
+        Some(match self {
+            Self::Ip(set) => set.contains(&end.ip),
+            Self::Mac(mac) => end.mac.as_ref() == Some(mac),
+            Self::Port(range) => end.port.is_some_and(|p| range.contains(p)),
+            Self::Proto(proto) => info.proto == Some(*proto),
+            Self::Domain(rgx) => return name_matches(rgx, rd, now, end),
+            Self::Sni(rgx) => matches(rgx, info.sni),
+            Self::Host(rgx) => matches(rgx, info.http_host),
+            Self::Path(rgx) => matches(rgx, info.http_path),
+        })
+    }
+
+    /// Tests that belong to the connection rather than to one of its ends
+    fn is_sideless(&self) -> bool {
+        matches!(
+            self,
+            Self::Proto(_) | Self::Sni(_) | Self::Host(_) | Self::Path(_)
+        )
+    }
+}
+
+/// Three valued and, false wins over unknown
+fn and(a: Option<bool>, b: impl FnOnce() -> Option<bool>) -> Option<bool> {
+    if a == Some(false) {
+        return a;
+    }
+    match b() {
+        Some(true) => a,
+        other => other,
+    }
+}
+
+/// Three valued or, true wins over unknown
+fn or(a: Option<bool>, b: impl FnOnce() -> Option<bool>) -> Option<bool> {
+    if a == Some(true) {
+        return a;
+    }
+    match b() {
+        Some(false) => a,
+        other => other,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Tilde,
+    Word(String),
+    Quoted(String),
+    End,
+}
+
+/// Parses a `match` expression, errors carry the column they were found at
+pub fn parse(input: &str) -> Result<Expr, ConfigError> {
+    let mut parser = Parser {
+        input,
+        tokens: tokenize(input)?,
+        pos: 0,
+        last: 0,
+    };
+    let expr = parser.or()?;
+    match parser.peek() {
+        Token::End => Ok(expr),
+        _ => Err(parser.error("expected `and`, `or` or the end of the expression")),
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ConfigError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().enumerate().peekable();
+    while let Some((col, c)) = chars.next() {
+        let col = col + 1;
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '~' => Token::Tilde,
+            '"' | '\'' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) if chars.peek().is_some_and(|(_, n)| *n == c) => {
+                            s.push(c);
+                            chars.next();
+                        }
+                        Some((_, q)) if q == c => break,
+                        Some((_, ch)) => s.push(ch),
+                        None => return Err(expr_error(input, col, "unterminated string")),
+                    }
+                }
+                Token::Quoted(s)
+            }
+            _ => {
+                let mut word = c.to_string();
+                while let Some((_, ch)) = chars.next_if(|(_, ch)| !is_delimiter(*ch)) {
+                    word.push(ch);
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push((col, token));
+    }
+    tokens.push((input.chars().count() + 1, Token::End));
+    Ok(tokens)
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '~' | '"' | '\'')
+}
+
+fn expr_error(input: &str, column: usize, reason: impl Into<String>) -> ConfigError {
+    ConfigError::InvalidExpression {
+        expr: input.to_string(),
+        column,
+        reason: reason.into(),
+    }
+}
+
+/// Recursive descent over `or` < `and` < `not` < tests and parentheses
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Index of the token read last
+    last: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].1
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].1.clone();
+        self.last = self.pos;
+        if token != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Word(w) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    /// Error at the token about to be read
+    fn error(&self, reason: impl Into<String>) -> ConfigError {
+        expr_error(self.input, self.tokens[self.pos].0, reason)
+    }
+
+    /// Error at the token just read
+    fn error_before(&self, reason: impl Into<String>) -> ConfigError {
+        expr_error(self.input, self.tokens[self.last].0, reason)
+    }
+
+    fn or(&mut self) -> Result<Expr, ConfigError> {
+        let mut expr = self.and()?;
+        while self.is_keyword("or") {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, ConfigError> {
+        let mut expr = self.not()?;
+        while self.is_keyword("and") {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, ConfigError> {
+        if self.is_keyword("not") {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if *self.peek() == Token::Open {
+            self.next();
+            let expr = self.or()?;
+            if self.next() != Token::Close {
+                return Err(self.error_before("expected `)`"));
+            }
+            return Ok(expr);
+        }
+        self.test()
+    }
+
+    fn test(&mut self) -> Result<Expr, ConfigError> {
+        let side = if self.is_keyword("src") {
+            self.next();
+            Side::Src
+        } else if self.is_keyword("dst") {
+            self.next();
+            Side::Dst
+        } else {
+            Side::Either
+        };
+        let Token::Word(kind) = self.next() else {
+            return Err(self.error_before(
+                "expected one of ip, net, mac, port, proto, domain, sni, host, path",
+            ));
+        };
+        let kind_column = self.tokens[self.last].0;
+        let predicate = match kind.to_lowercase().as_str() {
+            "ip" => {
+                let value = self.value()?;
+                let ip = value
+                    .parse::<IpAddr>()
+                    .map_err(|e| self.error_before(format!("{e} [{value}]")))?;
+                Predicate::Ip(std::iter::once(IpRange::from(ip)).collect())
+            }
+            "net" => {
+                let value = self.value()?;
+                let range = IpRange::try_from(value.as_str()).map_err(|e| self.invalid(e))?;
+                Predicate::Ip(std::iter::once(range).collect())
+            }
+            "mac" => {
+                let value = self.value()?;
+                Predicate::Mac(MacAddr::try_from(value.as_str()).map_err(|e| self.invalid(e))?)
+            }
+            "port" => {
+                let value = self.value()?;
+                Predicate::Port(PortRange::try_from(value.as_str()).map_err(|e| self.invalid(e))?)
+            }
+            "proto" => {
+                let value = self.value()?;
+                Predicate::Proto(
+                    TransportProto::try_from(value.as_str()).map_err(|e| self.invalid(e))?,
+                )
+            }
+            "domain" => Predicate::Domain(self.regex()?),
+            "sni" => Predicate::Sni(self.regex()?),
+            "host" => Predicate::Host(self.regex()?),
+            "path" => Predicate::Path(self.regex()?),
+            _ => {
+                return Err(self.error_before(format!(
+                    "unknown test `{kind}`, expected one of ip, net, mac, port, proto, domain, sni, host, path"
+                )))
+            }
+        };
+        if side != Side::Either && predicate.is_sideless() {
+            return Err(expr_error(
+                self.input,
+                kind_column,
+                format!("`{kind}` belongs to the connection, it can't be given src or dst"),
+            ));
+        }
+        Ok(Expr::Test(side, predicate))
+    }
+
+    fn value(&mut self) -> Result<String, ConfigError> {
+        match self.next() {
+            Token::Word(w) | Token::Quoted(w) => Ok(w),
+            _ => Err(self.error_before("expected a value")),
+        }
+    }
+
+    fn regex(&mut self) -> Result<Regex, ConfigError> {
+        if self.next() != Token::Tilde {
+            return Err(self.error_before("expected `~` followed by a regex"));
+        }
+        let pattern = self.value()?;
+        Regex::new(&pattern).map_err(|e| self.invalid(e))
+    }
+
+    fn invalid(&self, e: impl ToString) -> ConfigError {
+        self.error_before(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Expr, Predicate, Side};
+    use crate::{
+        domains::Resolved,
+        errors::ConfigError,
+        filters::{Endpoint, MacAddr, PacketInfo, TransportProto},
+    };
+    use std::time::Duration;
+
+    fn info<'a>(src: &str, dst: &str, dst_port: u16) -> PacketInfo<'a> {
+        PacketInfo {
+            src: Endpoint {
+                ip: src.parse().unwrap(),
+                mac: Some(MacAddr::build(&[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff])),
+                port: Some(51000),
+                server_name: None,
+            },
+            dst: Endpoint {
+                ip: dst.parse().unwrap(),
+                mac: None,
+                port: Some(dst_port),
+                server_name: Some("git.corp.example"),
+            },
+            proto: Some(TransportProto::Tcp),
+            sni: Some("git.corp.example"),
+            http_host: None,
+            http_path: None,
+        }
+    }
+
+    fn eval(expr: &str, info: &PacketInfo) -> Option<bool> {
+        let mut rd = Resolved::build(16, 1);
+        parse(expr).unwrap().eval(info, &mut rd, Duration::ZERO)
+    }
+
+    fn column(expr: &str) -> usize {
+        match parse(expr) {
+            Err(ConfigError::InvalidExpression { column, .. }) => column,
+            other => panic!("expected an expression error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn precedence_is_not_and_or() {
+        let expr = parse("ip 10.0.0.1 or not port 22 and proto tcp").unwrap();
+        let Expr::Or(_, right) = expr else {
+            panic!("or should be the root");
+        };
+        assert!(matches!(*right, Expr::And(..)));
+    }
+
+    #[test]
+    fn combined_sides_and_ports() {
+        let rule = r#"(src ip 10.0.0.5 or src mac aa:bb:cc:dd:ee:ff) and not dst domain ~ "\.corp\.example$" and dst port 443"#;
+        assert_eq!(eval(rule, &info("10.0.0.9", "10.1.0.1", 443)), Some(false));
+        let rule =
+            r#"(src ip 10.0.0.5 or src mac aa:bb:cc:dd:ee:ff) and sni ~ '^git\.' and dst port 443"#;
+        assert_eq!(eval(rule, &info("10.0.0.9", "10.1.0.1", 443)), Some(true));
+        assert_eq!(eval(rule, &info("10.0.0.9", "10.1.0.1", 80)), Some(false));
+    }
+
+    #[test]
+    fn unqualified_tests_look_at_both_ends() {
+        let info = info("10.0.0.9", "192.168.1.20", 443);
+        assert_eq!(eval("net 192.168.0.0/16", &info), Some(true));
+        assert_eq!(eval("src net 192.168.0.0/16", &info), Some(false));
+        assert!(matches!(
+            parse("port 1-1024").unwrap(),
+            Expr::Test(Side::Either, Predicate::Port(_))
+        ));
+    }
+
+    #[test]
+    fn errors_point_at_the_column() {
+        assert_eq!(column("src ip 10.0.0.300"), 8);
+        assert_eq!(column("ip 10.0.0.1 and (port 22"), 25);
+        assert_eq!(column("ip 10.0.0.1 port 22"), 13);
+        assert_eq!(column("src sni ~ x"), 5);
+        assert_eq!(column("domain \"x"), 8);
+        assert_eq!(column("dst addr 1.2.3.4"), 5);
+    }
+}
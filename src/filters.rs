@@ -1,52 +1,188 @@
-use crate::{domains::Resolved, errors::ConfigError};
+use crate::{
+    domains::{Lookup, Resolved},
+    errors::ConfigError,
+    expr::Expr,
+    flows::Flow,
+    ipset::IpSet,
+    packet_utils::{ParsedPacket, Transport},
+    quota::Quota,
+    schedule::Schedule,
+};
 use rand::Rng;
 use regex::Regex;
-use serde::Deserialize;
-use std::{fmt::Debug, net::IpAddr};
+use slog::info;
+use slog_scope::logger;
+use std::{fmt::Debug, net::IpAddr, time::Duration};
 
 #[derive(Debug, Clone)]
 pub struct Filter {
+    /// Position of the filter in the config file, from 1
+    pub rule: usize,
+    /// Filters with a higher priority are tried first, ties keep file order
+    pub priority: i64,
+    /// Disabled filters are skipped, they can be turned back on at runtime
+    pub enabled: bool,
     pub src: HostFilter,
     pub dst: HostFilter,
+    pub src_port: PortFilter,
+    pub dst_port: PortFilter,
+    pub proto: Option<TransportProto>,
+    /// Server name of the TLS connection, flows without one never match
+    pub sni: Option<Regex>,
+    /// Host and path of the first request of a plaintext HTTP connection,
+    /// flows without one never match
+    pub http_host: Option<Regex>,
+    pub http_path: Option<Regex>,
+    /// Parsed `match` expression, must hold on top of the other keys
+    pub expr: Option<Expr>,
+    /// Windows outside of which the filter is skipped
+    pub schedule: Option<Schedule>,
+    /// Limits of a `quota` filter
+    pub quota: Option<Quota>,
     pub mode: PacketAction,
     pub prob: f64,
+    /// Overrides the global dry-run setting for this filter
+    pub dry_run: Option<bool>,
+    /// Overrides the global policy for packets waiting on a reverse lookup
+    pub on_unresolved: Option<UnresolvedPolicy>,
 }
 
-impl PartialEq for Filter {
-    fn eq(&self, other: &Self) -> bool {
-        // discriminant(&self.src) == discriminant(&other.src) &&
-        // discriminant(&self.dst) == discriminant(&other.dst)
-        self.src.get_sort_val() + self.dst.get_sort_val()
-            == other.src.get_sort_val() + other.dst.get_sort_val()
-    }
+#[derive(Debug, Clone)]
+pub enum HostFilter {
+    WildCard,
+    IncludeIPs(IpSet),
+    ExcludeIPs(IpSet),
+    IncludeMACs(Vec<MacAddr>),
+    ExcludeMACs(Vec<MacAddr>),
+    Regex(Regex),
+    InGroup(HostGroup),
+    NotInGroup(HostGroup),
+}
+
+/// Hosts of the `@groups` a filter references, a host belongs to the group
+/// if its address, its MAC or one of its names matches
+#[derive(Clone)]
+pub struct HostGroup {
+    /// The references, like `@iot, @cameras`
+    pub name: String,
+    pub ips: IpSet,
+    pub macs: Vec<MacAddr>,
+    pub domains: Option<Regex>,
 }
-impl Eq for Filter {}
 
-impl PartialOrd for Filter {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        let src = self.src.get_sort_val() + self.dst.get_sort_val();
-        let dst = other.src.get_sort_val() + other.dst.get_sort_val();
-        src.partial_cmp(&dst)
+impl Debug for HostGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct(&self.name)
+            .field("ips", &self.ips)
+            .field("macs", &self.macs)
+            .field("domains", &self.domains.as_ref().map(Regex::as_str))
+            .finish()
     }
 }
 
-impl Ord for Filter {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+impl HostGroup {
+    /// `None` if that depends on a name still being resolved
+    fn contains(&self, rd: &mut Resolved, now: Duration, end: &Endpoint) -> Option<bool> {
+        if self.ips.contains(&end.ip) || end.mac.as_ref().is_some_and(|m| self.macs.contains(m)) {
+            return Some(true);
+        }
+        match &self.domains {
+            Some(rgx) => name_matches(rgx, rd, now, end),
+            None => Some(false),
+        }
+    }
+
+    fn covers(&self, other: &HostGroup) -> bool {
+        let domains = |g: &HostGroup| g.domains.as_ref().map(|r| r.as_str().to_string());
+        self.ips.covers(&other.ips)
+            && other.macs.iter().all(|m| self.macs.contains(m))
+            && (other.domains.is_none() || domains(self) == domains(other))
     }
 }
 
 #[derive(Debug, Clone)]
-pub enum HostFilter {
+pub enum PortFilter {
     WildCard,
-    IncludeIPs(Vec<IpAddr>),
-    ExcludeIPs(Vec<IpAddr>),
-    IncludeMACs(Vec<MacAddr>),
-    ExcludeMACs(Vec<MacAddr>),
-    Regex(Regex),
+    Ports(Vec<PortRange>),
+}
+
+/// Inclusive range of ports, a single port is a range with equal bounds
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
 }
 
-#[derive(Deserialize, Clone, Eq)]
+impl TryFrom<&str> for PortRange {
+    type Error = ConfigError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .map_err(|_| ConfigError::InvalidPort(value.to_string()))
+        };
+        let (start, end) = match value.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(value)?, parse(value)?),
+        };
+        if start > end {
+            return Err(ConfigError::InvalidPort(value.to_string()));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl From<u16> for PortRange {
+    fn from(port: u16) -> Self {
+        Self {
+            start: port,
+            end: port,
+        }
+    }
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl PortFilter {
+    pub fn matches(&self, port: Option<u16>) -> bool {
+        match self {
+            Self::WildCard => true,
+            Self::Ports(l) => port.is_some_and(|p| l.iter().any(|r| r.contains(p))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportProto {
+    Tcp,
+    Udp,
+}
+
+impl TransportProto {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        }
+    }
+}
+
+impl TryFrom<&str> for TransportProto {
+    type Error = ConfigError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            _ => Err(ConfigError::UnknownProto(value.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Eq)]
 pub struct MacAddr([u8; 6]);
 
 impl TryFrom<&str> for MacAddr {
@@ -87,12 +223,26 @@ impl MacAddr {
     }
 }
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub enum PacketAction {
     Reset,
     SynReset,
     Monitor,
     Ignore,
+    /// Monitor until the filter's quota is exceeded, then reset
+    Quota,
+}
+
+impl PacketAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Reset => "reset",
+            Self::SynReset => "syn_reset",
+            Self::Monitor => "monitor",
+            Self::Ignore => "ignore",
+            Self::Quota => "quota",
+        }
+    }
 }
 
 impl TryFrom<&str> for PacketAction {
@@ -103,109 +253,471 @@ impl TryFrom<&str> for PacketAction {
             "syn_reset" => Ok(Self::SynReset),
             "monitor" => Ok(Self::Monitor),
             "ignore" => Ok(Self::Ignore),
+            "quota" => Ok(Self::Quota),
             _ => Err(ConfigError::UnknownMode(value.to_string())),
         }
     }
 }
 
+/// What to do with a packet whose filter depends on a name that is still
+/// being reverse resolved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnresolvedPolicy {
+    Monitor,
+    Ignore,
+    Reset,
+}
+
+impl TryFrom<&str> for UnresolvedPolicy {
+    type Error = ConfigError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "monitor" => Ok(Self::Monitor),
+            "ignore" => Ok(Self::Ignore),
+            "reset" => Ok(Self::Reset),
+            _ => Err(ConfigError::UnknownPolicy(value.to_string())),
+        }
+    }
+}
+
+impl Filter {
+    /// Filter matching every packet, tests build theirs from it
+    #[cfg(test)]
+    pub fn any(mode: PacketAction) -> Self {
+        Self {
+            rule: 0,
+            priority: 0,
+            enabled: true,
+            src: HostFilter::WildCard,
+            dst: HostFilter::WildCard,
+            src_port: PortFilter::WildCard,
+            dst_port: PortFilter::WildCard,
+            proto: None,
+            sni: None,
+            http_host: None,
+            http_path: None,
+            expr: None,
+            schedule: None,
+            quota: None,
+            mode,
+            prob: 1.,
+            dry_run: None,
+            on_unresolved: None,
+        }
+    }
+
+    /// Whether every packet `other` matches is also matched by `self`
+    fn covers(&self, other: &Filter) -> bool {
+        let same_regex = |a: &Option<Regex>, b: &Option<Regex>| match (a, b) {
+            (None, _) => true,
+            (Some(a), Some(b)) => a.as_str() == b.as_str(),
+            (Some(_), None) => false,
+        };
+        (self.proto.is_none() || self.proto == other.proto)
+            && self.src_port.covers(&other.src_port)
+            && self.dst_port.covers(&other.dst_port)
+            && self.src.covers(&other.src)
+            && self.dst.covers(&other.dst)
+            && same_regex(&self.sni, &other.sni)
+            && same_regex(&self.http_host, &other.http_host)
+            && same_regex(&self.http_path, &other.http_path)
+            // expressions and schedules aren't compared
+            && self.expr.is_none()
+            && self.schedule.is_none()
+    }
+
+    /// Checks the transport protocol and ports of a packet against the filter
+    pub fn matches_transport(
+        &self,
+        proto: Option<TransportProto>,
+        ports: Option<(u16, u16)>,
+    ) -> bool {
+        if self.proto.is_some() && self.proto != proto {
+            return false;
+        }
+        self.src_port.matches(ports.map(|(src, _)| src))
+            && self.dst_port.matches(ports.map(|(_, dst)| dst))
+    }
+}
+
 impl HostFilter {
-    pub fn get_sort_val(&self) -> u8 {
-        match self {
-            Self::WildCard => 0,
-            Self::IncludeIPs(_) => 1,
-            Self::ExcludeIPs(_) => 1,
-            Self::IncludeMACs(_) => 2,
-            Self::ExcludeMACs(_) => 2,
-            Self::Regex(_) => 4,
+    /// Whether every host this filter matches is also matched by `self`,
+    /// regexes only cover identical ones
+    fn covers(&self, other: &HostFilter) -> bool {
+        match (self, other) {
+            (Self::WildCard, _) => true,
+            (Self::IncludeIPs(a), Self::IncludeIPs(b)) => a.covers(b),
+            (Self::ExcludeIPs(a), Self::ExcludeIPs(b)) => b.covers(a),
+            (Self::IncludeMACs(a), Self::IncludeMACs(b)) => b.iter().all(|m| a.contains(m)),
+            (Self::ExcludeMACs(a), Self::ExcludeMACs(b)) => a.iter().all(|m| b.contains(m)),
+            (Self::Regex(a), Self::Regex(b)) => a.as_str() == b.as_str(),
+            (Self::InGroup(a), Self::InGroup(b)) => a.covers(b),
+            (Self::NotInGroup(a), Self::NotInGroup(b)) => b.covers(a),
+            _ => false,
+        }
+    }
+}
+
+impl PortFilter {
+    fn covers(&self, other: &PortFilter) -> bool {
+        match (self, other) {
+            (Self::WildCard, _) => true,
+            (Self::Ports(a), Self::Ports(b)) => b
+                .iter()
+                .all(|r| a.iter().any(|o| o.start <= r.start && r.end <= o.end)),
+            (Self::Ports(_), Self::WildCard) => false,
         }
     }
 }
 
+/// Sorts filters in evaluation order, by descending priority then by their
+/// position in the config file
+pub fn sort_by_priority(filters: &mut [Filter]) {
+    // the sort is stable, equal priorities keep file order
+    filters.sort_by_key(|f| std::cmp::Reverse(f.priority));
+}
+
+/// Filters that can never match because an earlier one in evaluation order
+/// matches every packet they would, as `(shadowed, by)` index pairs. The
+/// first matching filter decides, whatever its mode and prob. Conservative:
+/// only shadowing that can be proven from the keys is reported.
+pub fn shadowed(filters: &[Filter]) -> Vec<(usize, usize)> {
+    filters
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, later)| {
+            let by = filters[..idx]
+                .iter()
+                .position(|f| f.enabled && f.covers(later))?;
+            Some((idx, by))
+        })
+        .collect()
+}
+
+/// Result of matching a packet against the filters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Match {
+    Miss,
+    Filter(usize),
+    /// The filter may match once a name being reverse resolved is known,
+    /// the filters after it weren't tried
+    Pending(usize),
+}
+
+/// Outcome of matching a packet against the blacklist
+#[derive(Debug, Clone, Copy)]
+pub struct Verdict {
+    /// Index of the first filter the packet matched, in evaluation order
+    pub filter: Option<usize>,
+    /// What to do with the packet, `Ignore` if the filter's prob roll failed
+    pub action: PacketAction,
+    /// The action comes from the `on_unresolved` policy, not the filter
+    pub unresolved: bool,
+}
+
 pub struct Blacklist {
     list: Vec<Filter>,
+    on_unresolved: UnresolvedPolicy,
+    /// Whether the schedule of each filter lets it apply
+    active: Vec<bool>,
+    /// Capture second the schedules were last checked at
+    schedules_checked: Option<u64>,
 }
 
 impl Blacklist {
-    pub fn build(list: &[Filter]) -> Self {
+    pub fn build(list: &[Filter], on_unresolved: UnresolvedPolicy) -> Self {
         Self {
             list: list.to_vec(),
+            on_unresolved,
+            active: vec![true; list.len()],
+            schedules_checked: None,
         }
     }
+
+    /// Checks the schedules, at most once per second of capture time, and
+    /// logs the filters turning on or off. Returns whether any did.
+    pub fn update_schedules(&mut self, now: Duration) -> bool {
+        if self.schedules_checked == Some(now.as_secs()) {
+            return false;
+        }
+        self.schedules_checked = Some(now.as_secs());
+        let mut changed = false;
+        for (idx, f) in self.list.iter().enumerate() {
+            let Some(schedule) = &f.schedule else {
+                continue;
+            };
+            let active = schedule.is_active(now);
+            if active != self.active[idx] {
+                info!(
+                    logger(),
+                    "Filter #{} (rule {}) is now {}",
+                    idx + 1,
+                    f.rule,
+                    if active { "active" } else { "inactive" }
+                );
+                self.active[idx] = active;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub fn filters(&self) -> &[Filter] {
+        &self.list
+    }
+
+    /// Whether the schedule of the filter at `idx` lets it apply right now
+    pub fn in_schedule(&self, idx: usize) -> bool {
+        self.active[idx]
+    }
+
+    /// Inserts a filter in evaluation order, after the ones of the same
+    /// priority
+    pub fn add(&mut self, filter: Filter) {
+        let idx = self
+            .list
+            .iter()
+            .position(|f| f.priority < filter.priority)
+            .unwrap_or(self.list.len());
+        self.list.insert(idx, filter);
+        self.active.insert(idx, true);
+        // the new filter's schedule is checked with the next packet
+        self.schedules_checked = None;
+    }
+
+    /// Removes the filter with the given rule id
+    pub fn remove(&mut self, rule: usize) -> Result<Filter, ConfigError> {
+        let idx = self.position(rule)?;
+        self.active.remove(idx);
+        Ok(self.list.remove(idx))
+    }
+
+    pub fn set_enabled(&mut self, rule: usize, enabled: bool) -> Result<(), ConfigError> {
+        let idx = self.position(rule)?;
+        self.list[idx].enabled = enabled;
+        Ok(())
+    }
+
+    fn position(&self, rule: usize) -> Result<usize, ConfigError> {
+        self.list
+            .iter()
+            .position(|f| f.rule == rule)
+            .ok_or(ConfigError::UnknownRule(rule))
+    }
     pub fn get_packet_action(
         &self,
-        tcp_details: (IpAddr, u16, &[u8; 6], IpAddr, u16, &[u8; 6]),
-        rd: &Resolved,
-    ) -> PacketAction {
-        let (src, _src_port, src_mac, dst, _dst_port, dst_mac) = tcp_details;
-        let matched = self.list.iter().find(|filter| {
-            self.in_filter(&filter.src, rd, src, MacAddr(*src_mac))
-                && self.in_filter(&filter.dst, rd, dst, MacAddr(*dst_mac))
-        });
-        if let Some(f) = matched {
-            if let PacketAction::Reset = f.mode {
-                if f.prob < rand::thread_rng().gen_range(0. ..1.) {
-                    return PacketAction::Ignore;
+        packet: &ParsedPacket,
+        rd: &mut Resolved,
+        now: Duration,
+    ) -> Verdict {
+        self.verdict_for(self.match_packet(packet, rd, now, None))
+    }
+
+    /// First filter matching the packet, the expensive part of a verdict
+    /// that flows cache per connection. The flow adds what was learned from
+    /// earlier packets, like the TLS server name.
+    pub fn match_packet(
+        &self,
+        packet: &ParsedPacket,
+        rd: &mut Resolved,
+        now: Duration,
+        flow: Option<&Flow>,
+    ) -> Match {
+        let info = PacketInfo::new(packet, flow);
+        let ports = info.src.port.zip(info.dst.port);
+        for (idx, filter) in self.list.iter().enumerate() {
+            if !filter.enabled || !self.active[idx] {
+                continue;
+            }
+            if !filter.matches_transport(info.proto, ports) {
+                continue;
+            }
+            if !regex_matches(&filter.sni, info.sni)
+                || !regex_matches(&filter.http_host, info.http_host)
+                || !regex_matches(&filter.http_path, info.http_path)
+            {
+                continue;
+            }
+            let src = self.in_filter(&filter.src, rd, now, &info.src);
+            if src == Some(false) {
+                continue;
+            }
+            // still asked when src is pending, so both names get queued
+            let dst = self.in_filter(&filter.dst, rd, now, &info.dst);
+            if dst == Some(false) {
+                continue;
+            }
+            let expr = match &filter.expr {
+                Some(expr) => expr.eval(&info, rd, now),
+                None => Some(true),
+            };
+            match (src, dst, expr) {
+                (_, _, Some(false)) => continue,
+                (Some(true), Some(true), Some(true)) => return Match::Filter(idx),
+                _ => return Match::Pending(idx),
+            }
+        }
+        Match::Miss
+    }
+
+    /// Turns a match into a verdict, rolling the filter's prob for every
+    /// packet so a cached match still resets only a share of them
+    pub fn verdict_for(&self, matched: Match) -> Verdict {
+        let idx = match matched {
+            Match::Miss => {
+                return Verdict {
+                    filter: None,
+                    action: PacketAction::Ignore,
+                    unresolved: false,
                 }
             }
+            Match::Pending(idx) => {
+                let policy = self.list[idx].on_unresolved.unwrap_or(self.on_unresolved);
+                return Verdict {
+                    filter: Some(idx),
+                    action: match policy {
+                        UnresolvedPolicy::Monitor => PacketAction::Monitor,
+                        UnresolvedPolicy::Ignore => PacketAction::Ignore,
+                        UnresolvedPolicy::Reset => PacketAction::Reset,
+                    },
+                    unresolved: true,
+                };
+            }
+            Match::Filter(idx) => idx,
+        };
+        let f = &self.list[idx];
+        let mut action = f.mode;
+        if let PacketAction::Reset | PacketAction::SynReset = f.mode {
+            if f.prob < rand::thread_rng().gen_range(0. ..1.) {
+                action = PacketAction::Ignore;
+            }
+        }
+        Verdict {
+            filter: Some(idx),
+            action,
+            unresolved: false,
         }
-        matched.map_or(PacketAction::Ignore, |f| f.mode)
     }
 
+    /// Whether an end of a packet matches a host filter, `None` if that
+    /// depends on a name still being resolved
     fn in_filter(
         &self,
         filter: &HostFilter,
-        rd: &Resolved,
-        ip_addr: IpAddr,
-        mac_addr: MacAddr,
-    ) -> bool {
+        rd: &mut Resolved,
+        now: Duration,
+        end: &Endpoint,
+    ) -> Option<bool> {
         match filter {
-            HostFilter::WildCard => true,
-            HostFilter::IncludeIPs(l) => l.iter().any(|i| *i == ip_addr),
-            HostFilter::ExcludeIPs(l) => l.iter().any(|i| *i != ip_addr),
-            HostFilter::IncludeMACs(l) => l.iter().any(|i| *i == mac_addr),
-            HostFilter::ExcludeMACs(l) => l.iter().any(|i| *i == mac_addr),
+            HostFilter::WildCard => Some(true),
+            HostFilter::IncludeIPs(s) => Some(s.contains(&end.ip)),
+            HostFilter::ExcludeIPs(s) => Some(!s.contains(&end.ip)),
+            // cooked captures don't record every MAC, a missing one is in no list
+            HostFilter::IncludeMACs(l) => Some(end.mac.as_ref().is_some_and(|m| l.contains(m))),
+            HostFilter::ExcludeMACs(l) => Some(!end.mac.as_ref().is_some_and(|m| l.contains(m))),
             HostFilter::Regex(rgx) => {
-                let mut domain: Option<String> = rd.get(&ip_addr);
-                if domain.is_none() {
-                    domain = rd.resolve(&ip_addr).ok();
-                };
-                if let Some(d) = domain {
-                    if rgx.is_match(&d) {
-                        return true;
-                    }
+                // a matching IP makes the name irrelevant, no lookup needed
+                if rgx.is_match(&end.ip.to_string()) {
+                    return Some(true);
                 }
-
-                let ip = match ip_addr {
-                    IpAddr::V4(adr) => {
-                        let segments: Vec<String> =
-                            adr.octets().iter().map(|oct| format!("{oct}")).collect();
-                        segments.join(".")
-                    }
-                    IpAddr::V6(adr) => {
-                        let segments: Vec<String> = adr
-                            .octets()
-                            .iter()
-                            .map(|oct| format!("{oct:04X}"))
-                            .collect();
-                        segments.join(":")
-                    }
-                };
-                rgx.is_match(&ip)
+                name_matches(rgx, rd, now, end)
             }
+            HostFilter::InGroup(g) => g.contains(rd, now, end),
+            HostFilter::NotInGroup(g) => g.contains(rd, now, end).map(|v| !v),
         }
     }
 }
 
+/// Whether a name of an end of a packet matches, either the one a TLS or
+/// HTTP client asked it for or one learned from DNS. `None` if that depends
+/// on a name still being resolved.
+pub fn name_matches(rgx: &Regex, rd: &mut Resolved, now: Duration, end: &Endpoint) -> Option<bool> {
+    if end.server_name.is_some_and(|n| rgx.is_match(n)) {
+        return Some(true);
+    }
+    match rd.name(&end.ip, now) {
+        Lookup::Found(d) => Some(d.names().any(|n| rgx.is_match(n))),
+        Lookup::Unresolvable => Some(false),
+        Lookup::Pending => None,
+    }
+}
+
+/// What filters can look at, taken from a packet and its flow
+pub struct PacketInfo<'a> {
+    pub src: Endpoint<'a>,
+    pub dst: Endpoint<'a>,
+    pub proto: Option<TransportProto>,
+    pub sni: Option<&'a str>,
+    pub http_host: Option<&'a str>,
+    pub http_path: Option<&'a str>,
+}
+
+/// One end of a packet
+pub struct Endpoint<'a> {
+    pub ip: IpAddr,
+    pub mac: Option<MacAddr>,
+    pub port: Option<u16>,
+    /// Name a TLS or HTTP client asked this host for
+    pub server_name: Option<&'a str>,
+}
+
+impl<'a> PacketInfo<'a> {
+    pub fn new(packet: &ParsedPacket, flow: Option<&'a Flow>) -> Self {
+        let (src, dst) = (packet.ip().src, packet.ip().dst);
+        let ports = packet.ports();
+        let http = flow.and_then(|f| f.http.as_ref());
+        // the server name belongs to the responder, whichever way the packet goes
+        let server_name = flow.and_then(|f| f.server_name());
+        let server = flow.map(|f| f.responder.ip());
+        Self {
+            src: Endpoint {
+                ip: src,
+                mac: packet.link().src_mac.map(MacAddr::build),
+                port: ports.map(|(p, _)| p),
+                server_name: server_name.filter(|_| server == Some(src)),
+            },
+            dst: Endpoint {
+                ip: dst,
+                mac: packet.link().dst_mac.map(MacAddr::build),
+                port: ports.map(|(_, p)| p),
+                server_name: server_name.filter(|_| server == Some(dst)),
+            },
+            proto: match packet.transport() {
+                Transport::Tcp(_) => Some(TransportProto::Tcp),
+                Transport::Udp(_) => Some(TransportProto::Udp),
+                Transport::Other(_) => None,
+            },
+            sni: flow.and_then(|f| f.sni.as_deref()),
+            http_host: http.and_then(|r| r.host.as_deref()),
+            http_path: http.map(|r| r.path.as_str()),
+        }
+    }
+}
+
+/// An optional regex on something a flow may not know, unset matches anything
+fn regex_matches(rgx: &Option<Regex>, value: Option<&str>) -> bool {
+    rgx.as_ref()
+        .is_none_or(|r| value.is_some_and(|v| r.is_match(v)))
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(dead_code)]
     use regex::Regex;
 
     // use crate::configs::Config;
-    use super::{Filter, HostFilter, MacAddr, PacketAction};
+    use crate::ipset::{IpRange, IpSet};
+
+    use crate::domains::Resolved;
+
+    use super::{
+        regex_matches, shadowed, sort_by_priority, Blacklist, Endpoint, Filter, HostFilter,
+        MacAddr, Match, PacketAction, PortFilter, PortRange, TransportProto, UnresolvedPolicy,
+    };
     use std::{
         assert_ne,
         net::{IpAddr, Ipv4Addr},
+        time::Duration,
     };
     enum FilterType {
         WildCard,
@@ -216,11 +728,14 @@ mod tests {
     // static config :Config = Config::build().unwrap();
     fn create_filter(src: FilterType, dst: FilterType, mode: PacketAction, prob: f64) -> Filter {
         let default = |c| {
-            let ip_vec = vec![
+            let ip_vec: IpSet = [
                 IpAddr::V4("192.235.32.2".parse::<Ipv4Addr>().unwrap()),
                 IpAddr::V4("192.255.32.2".parse::<Ipv4Addr>().unwrap()),
                 IpAddr::V4("193.255.32.2".parse::<Ipv4Addr>().unwrap()),
-            ];
+            ]
+            .into_iter()
+            .map(IpRange::from)
+            .collect();
             let rgx = Regex::new("(httpbin|lobste)").unwrap();
             match c {
                 FilterType::WildCard => HostFilter::WildCard,
@@ -233,23 +748,40 @@ mod tests {
         Filter {
             src: default(src),
             dst: default(dst),
-            mode,
             prob,
+            ..Filter::any(mode)
         }
     }
     #[test]
-    fn similar_filters_are_equal() {
+    fn priority_wins_then_file_order() {
         use FilterType::*;
-        let a = create_filter(WildCard, Regex, PacketAction::Reset, 0.1);
-        let b = create_filter(Regex, WildCard, PacketAction::Ignore, 1.);
-        assert!(a == b);
+        let mut filters: Vec<Filter> = [0, 5, 0, 5]
+            .into_iter()
+            .enumerate()
+            .map(|(idx, priority)| {
+                let mut f = create_filter(WildCard, Regex, PacketAction::Reset, 1.);
+                f.rule = idx + 1;
+                f.priority = priority;
+                f
+            })
+            .collect();
+        sort_by_priority(&mut filters);
+        let rules: Vec<usize> = filters.iter().map(|f| f.rule).collect();
+        assert_eq!(rules, [2, 4, 1, 3]);
     }
     #[test]
-    fn wildcard_beats_list() {
+    fn rules_behind_broader_ones_are_shadowed() {
         use FilterType::*;
-        let a = create_filter(WildCard, WildCard, PacketAction::Ignore, 0.3);
-        let b = create_filter(WildCard, IncludeIPs, PacketAction::Reset, 0.4);
-        assert!(a < b);
+        let wide = create_filter(WildCard, WildCard, PacketAction::Ignore, 0.3);
+        let mut narrow = create_filter(WildCard, IncludeIPs, PacketAction::Reset, 0.4);
+        narrow.dst_port = PortFilter::Ports(vec![PortRange::from(443)]);
+        assert_eq!(shadowed(&[wide.clone(), narrow.clone()]), [(1, 0)]);
+        assert!(shadowed(&[narrow.clone(), wide]).is_empty());
+
+        let mut wider_ports = narrow.clone();
+        wider_ports.dst_port = PortFilter::Ports(vec![PortRange::try_from("1-1024").unwrap()]);
+        assert_eq!(shadowed(&[wider_ports.clone(), narrow.clone()]), [(1, 0)]);
+        assert!(shadowed(&[narrow, wider_ports]).is_empty());
     }
     #[test]
     fn similar_macs_are_equal() {
@@ -266,9 +798,138 @@ mod tests {
         assert_ne!(a, b);
     }
     #[test]
+    fn exclude_lists_match_everything_but_their_hosts() {
+        let bl = Blacklist::build(&[], UnresolvedPolicy::Ignore);
+        let mut rd = Resolved::build(16, 1);
+        let mut check = |filter: &HostFilter, ip: IpAddr, mac: &MacAddr| {
+            let end = Endpoint {
+                ip,
+                mac: Some(mac.clone()),
+                port: None,
+                server_name: None,
+            };
+            bl.in_filter(filter, &mut rd, Duration::ZERO, &end).unwrap()
+        };
+        let listed: IpAddr = "192.168.0.103".parse().unwrap();
+        let other: IpAddr = "192.168.0.104".parse().unwrap();
+        let mac = MacAddr::build(&[0x84, 0xc5, 0xa6, 0x15, 0x29, 0xd0]);
+        let other_mac = MacAddr::build(&[0x54, 0xc5, 0xa6, 0x15, 0x29, 0xd0]);
+        let ips = HostFilter::ExcludeIPs(
+            [listed, "192.168.0.1".parse().unwrap()]
+                .into_iter()
+                .map(IpRange::from)
+                .collect(),
+        );
+        assert!(!check(&ips, listed, &mac));
+        assert!(check(&ips, other, &mac));
+        let macs = HostFilter::ExcludeMACs(vec![mac.clone()]);
+        assert!(!check(&macs, other, &mac));
+        assert!(check(&macs, other, &other_mac));
+    }
+    #[test]
     #[should_panic(expected = "InvalidMacAddr")]
     fn panics_on_invalid_str_to_mac() {
         let mac = "84:c5:a6:15:2z:d0";
         let _val = MacAddr::try_from(mac).unwrap();
     }
+    #[test]
+    fn port_ranges_parse() {
+        assert_eq!(PortRange::try_from("443").unwrap(), PortRange::from(443));
+        let range = PortRange::try_from("8000-8100").unwrap();
+        assert!(range.contains(8000) && range.contains(8050) && range.contains(8100));
+        assert!(!range.contains(7999) && !range.contains(8101));
+    }
+    #[test]
+    #[should_panic(expected = "InvalidPort")]
+    fn panics_on_reversed_port_range() {
+        let _val = PortRange::try_from("8100-8000").unwrap();
+    }
+    #[test]
+    #[should_panic(expected = "InvalidPort")]
+    fn panics_on_out_of_range_port() {
+        let _val = PortRange::try_from("70000").unwrap();
+    }
+    #[test]
+    fn port_filters_match_on_ports() {
+        use FilterType::*;
+        let mut f = create_filter(WildCard, WildCard, PacketAction::Reset, 1.);
+        f.dst_port = PortFilter::Ports(vec![PortRange::from(22), PortRange::from(443)]);
+        assert!(f.matches_transport(Some(TransportProto::Tcp), Some((51000, 22))));
+        assert!(!f.matches_transport(Some(TransportProto::Tcp), Some((443, 80))));
+        assert!(!f.matches_transport(None, None));
+    }
+    #[test]
+    fn proto_filters_match_on_proto() {
+        use FilterType::*;
+        let mut f = create_filter(WildCard, WildCard, PacketAction::Reset, 1.);
+        f.proto = Some(TransportProto::Udp);
+        assert!(f.matches_transport(Some(TransportProto::Udp), Some((53, 5353))));
+        assert!(!f.matches_transport(Some(TransportProto::Tcp), Some((53, 5353))));
+    }
+    #[test]
+    fn pending_matches_follow_the_unresolved_policy() {
+        use FilterType::*;
+        let a = create_filter(WildCard, Regex, PacketAction::Reset, 1.);
+        let mut b = a.clone();
+        b.on_unresolved = Some(UnresolvedPolicy::Reset);
+        let blacklist = Blacklist::build(&[a, b], UnresolvedPolicy::Monitor);
+
+        let verdict = blacklist.verdict_for(Match::Pending(0));
+        assert!(verdict.unresolved);
+        assert!(matches!(verdict.action, PacketAction::Monitor));
+        let verdict = blacklist.verdict_for(Match::Pending(1));
+        assert!(matches!(verdict.action, PacketAction::Reset));
+        assert!(!blacklist.verdict_for(Match::Filter(1)).unresolved);
+    }
+    #[test]
+    fn regex_matches_the_tls_server_name() {
+        use FilterType::*;
+        let f = create_filter(WildCard, Regex, PacketAction::Reset, 1.);
+        let blacklist = Blacklist::build(std::slice::from_ref(&f), UnresolvedPolicy::Ignore);
+        let mut rd = Resolved::build(16, 1);
+        let end = Endpoint {
+            ip: "203.0.113.7".parse().unwrap(),
+            mac: None,
+            port: Some(443),
+            server_name: Some("lobste.rs"),
+        };
+        let matched = blacklist.in_filter(&f.dst, &mut rd, Duration::ZERO, &end);
+        assert_eq!(matched, Some(true));
+    }
+    #[test]
+    fn unset_request_regexes_match_anything() {
+        let rgx = Some(Regex::new("^/admin").unwrap());
+        assert!(regex_matches(&rgx, Some("/admin/users")));
+        assert!(!regex_matches(&rgx, Some("/")));
+        // a flow that isn't plaintext HTTP has no path to match
+        assert!(!regex_matches(&rgx, None));
+        assert!(regex_matches(&None, None));
+    }
+    #[test]
+    fn filters_change_at_runtime() {
+        use FilterType::*;
+        let filters: Vec<Filter> = [5, 0]
+            .into_iter()
+            .enumerate()
+            .map(|(idx, priority)| {
+                let mut f = create_filter(WildCard, WildCard, PacketAction::Reset, 1.);
+                f.rule = idx + 1;
+                f.priority = priority;
+                f
+            })
+            .collect();
+        let mut blacklist = Blacklist::build(&filters, UnresolvedPolicy::Ignore);
+        let mut added = create_filter(WildCard, WildCard, PacketAction::Monitor, 1.);
+        added.rule = 3;
+        added.priority = 5;
+        blacklist.add(added);
+        let rules: Vec<usize> = blacklist.filters().iter().map(|f| f.rule).collect();
+        assert_eq!(rules, [1, 3, 2]);
+
+        blacklist.set_enabled(1, false).unwrap();
+        assert!(!blacklist.filters()[0].enabled);
+        assert_eq!(blacklist.remove(2).unwrap().rule, 2);
+        assert!(blacklist.remove(2).is_err());
+        assert!(blacklist.set_enabled(7, true).is_err());
+    }
 }
@@ -0,0 +1,468 @@
+use slog::{debug, warn};
+use slog_scope::logger;
+use std::{collections::HashMap, fmt::Display, net::SocketAddr, time::Duration};
+
+use crate::{
+    filters::{Match, TransportProto},
+    http::Request,
+    packet_utils::{ParsedPacket, Transport},
+};
+
+/// Connections that never complete their handshake are dropped quickly
+const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Closed connections linger a little to absorb retransmitted FINs and RSTs
+const TCP_CLOSED_TIMEOUT: Duration = Duration::from_secs(10);
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Direction-independent 5-tuple, both directions of a connection map to the
+/// same key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    proto: u8,
+    lo: SocketAddr,
+    hi: SocketAddr,
+}
+
+impl FlowKey {
+    pub fn new(proto: TransportProto, a: SocketAddr, b: SocketAddr) -> Self {
+        let proto = match proto {
+            TransportProto::Tcp => 6,
+            TransportProto::Udp => 17,
+        };
+        let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+        Self { proto, lo, hi }
+    }
+}
+
+/// Which side of the connection sent a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the host that opened the connection
+    Forward,
+    Reverse,
+}
+
+impl Direction {
+    fn idx(self) -> usize {
+        match self {
+            Self::Forward => 0,
+            Self::Reverse => 1,
+        }
+    }
+}
+
+/// TCP connection state as seen from the wire, coarser than the endpoints' own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    SynSent,
+    Established,
+    FinWait,
+    Closed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Flow {
+    pub proto: TransportProto,
+    /// Host that opened the connection, or sent the first packet seen if the
+    /// flow was picked up mid-stream
+    pub initiator: SocketAddr,
+    pub responder: SocketAddr,
+    /// `None` for UDP
+    pub state: Option<TcpState>,
+    /// Last sequence number seen, per direction
+    pub last_seq: [Option<u32>; 2],
+    pub packets: [u64; 2],
+    /// Transport payload bytes, per direction
+    pub bytes: [u64; 2],
+    /// Capture timestamps of the first and latest packet
+    pub first_seen: Duration,
+    pub last_seen: Duration,
+    /// Server name the initiator asked for in its TLS ClientHello
+    pub sni: Option<String>,
+    /// First request of a plaintext HTTP connection
+    pub http: Option<Request>,
+    /// The initiator's first bytes are still being looked at for a
+    /// ClientHello or an HTTP request
+    pub inspecting: bool,
+    /// The flow went over a limit of a quota filter, its own or its host's
+    pub quota_tripped: bool,
+    /// Payload bytes, both directions, already counted against the
+    /// initiator's quota
+    pub quota_counted: u64,
+    /// Cached filter match per direction
+    matched: [Option<Match>; 2],
+    fin: [bool; 2],
+}
+
+impl Flow {
+    pub fn new(
+        proto: TransportProto,
+        initiator: SocketAddr,
+        responder: SocketAddr,
+        ts: Duration,
+    ) -> Self {
+        Self {
+            proto,
+            initiator,
+            responder,
+            state: match proto {
+                TransportProto::Tcp => Some(TcpState::Established),
+                TransportProto::Udp => None,
+            },
+            last_seq: [None; 2],
+            packets: [0; 2],
+            bytes: [0; 2],
+            first_seen: ts,
+            last_seen: ts,
+            sni: None,
+            http: None,
+            inspecting: proto == TransportProto::Tcp,
+            quota_tripped: false,
+            quota_counted: 0,
+            matched: [None; 2],
+            fin: [false; 2],
+        }
+    }
+
+    /// Filter match cached for packets going in `dir`, `None` until the first
+    /// one has been evaluated
+    pub fn matched(&self, dir: Direction) -> Option<Match> {
+        self.matched[dir.idx()]
+    }
+
+    pub fn set_matched(&mut self, dir: Direction, matched: Match) {
+        self.matched[dir.idx()] = Some(matched);
+    }
+
+    /// Name the initiator asked the responder for, over TLS or HTTP
+    pub fn server_name(&self) -> Option<&str> {
+        let http_host = self.http.as_ref().and_then(|r| r.host.as_deref());
+        self.sni.as_deref().or(http_host)
+    }
+
+    /// Forgets the cached matches, for when something filters look at changed
+    pub fn clear_matched(&mut self) {
+        self.matched = [None; 2];
+    }
+
+    fn timeout(&self) -> Duration {
+        match self.state {
+            None => UDP_IDLE_TIMEOUT,
+            Some(TcpState::SynSent) => TCP_HANDSHAKE_TIMEOUT,
+            Some(TcpState::Established | TcpState::FinWait) => TCP_IDLE_TIMEOUT,
+            Some(TcpState::Closed) => TCP_CLOSED_TIMEOUT,
+        }
+    }
+
+    fn update(&mut self, packet: &ParsedPacket, dir: Direction, ts: Duration) {
+        let d = dir.idx();
+        self.last_seen = ts;
+        self.packets[d] += 1;
+        match packet.transport() {
+            Transport::Tcp(tcp) => {
+                self.bytes[d] += tcp.payload.len() as u64;
+                self.last_seq[d] = Some(tcp.seq_num);
+                if tcp.is_fin() {
+                    self.fin[d] = true;
+                }
+                self.state = Some(if tcp.is_rst() || self.fin == [true; 2] {
+                    TcpState::Closed
+                } else if self.fin.contains(&true) {
+                    TcpState::FinWait
+                } else if tcp.is_syn() {
+                    TcpState::SynSent
+                } else if tcp.is_ack() {
+                    TcpState::Established
+                } else {
+                    self.state.unwrap_or(TcpState::Established)
+                });
+            }
+            Transport::Udp(udp) => self.bytes[d] += udp.payload.len() as u64,
+            Transport::Other(_) => {}
+        }
+    }
+}
+
+/// Connection table keyed by 5-tuple, timed with capture timestamps so a
+/// replayed capture expires flows the same way a live one does
+pub struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    /// Most flows tracked at once, a flood of new connections evicts the
+    /// least recently seen ones instead of growing the table
+    max_flows: usize,
+}
+
+impl FlowTable {
+    pub fn new(max_flows: usize) -> Self {
+        Self {
+            flows: HashMap::new(),
+            max_flows,
+        }
+    }
+
+    /// Updates the flow a TCP or UDP packet belongs to, creating it if needed,
+    /// and returns it along with the direction of the packet
+    pub fn track(&mut self, packet: &ParsedPacket, ts: Duration) -> Option<(&mut Flow, Direction)> {
+        let (proto, src_port, dst_port, opens) = match packet.transport() {
+            Transport::Tcp(tcp) => (
+                TransportProto::Tcp,
+                tcp.src_port,
+                tcp.dst_port,
+                tcp.is_syn() && !tcp.is_ack(),
+            ),
+            Transport::Udp(udp) => (TransportProto::Udp, udp.src_port, udp.dst_port, false),
+            Transport::Other(_) => return None,
+        };
+        let src = SocketAddr::new(packet.ip().src, src_port);
+        let dst = SocketAddr::new(packet.ip().dst, dst_port);
+        let key = FlowKey::new(proto, src, dst);
+
+        // a SYN on a closed or expired flow starts a new connection reusing the ports
+        let stale = self.flows.get(&key).is_some_and(|flow| {
+            ts.saturating_sub(flow.last_seen) > flow.timeout()
+                || (opens && flow.state == Some(TcpState::Closed))
+        });
+        if stale {
+            self.flows.remove(&key);
+        }
+        if self.flows.len() >= self.max_flows && !self.flows.contains_key(&key) {
+            self.make_room(ts);
+        }
+        let flow = self.flows.entry(key).or_insert_with(|| match packet.tcp() {
+            // a SYN+ACK comes from the responder
+            Some(tcp) if tcp.is_syn() && tcp.is_ack() => Flow::new(proto, dst, src, ts),
+            _ => Flow::new(proto, src, dst, ts),
+        });
+        let dir = if flow.initiator == src {
+            Direction::Forward
+        } else {
+            Direction::Reverse
+        };
+        flow.update(packet, dir, ts);
+        Some((flow, dir))
+    }
+
+    /// Drops flows idle for longer than their state allows and returns them
+    pub fn expire(&mut self, now: Duration) -> Vec<Flow> {
+        let expired: Vec<FlowKey> = self
+            .flows
+            .iter()
+            .filter(|(_, flow)| now.saturating_sub(flow.last_seen) > flow.timeout())
+            .map(|(key, _)| *key)
+            .collect();
+        expired
+            .iter()
+            .filter_map(|key| self.flows.remove(key))
+            .collect()
+    }
+
+    /// Drops the idle flows, then the least recently seen ones if the table
+    /// is still full. An eighth of the table goes at once so a flood doesn't
+    /// scan it for every new connection.
+    fn make_room(&mut self, now: Duration) {
+        for flow in self.expire(now) {
+            debug!(logger(), "Flow expired: {}", flow);
+        }
+        if self.flows.len() < self.max_flows {
+            return;
+        }
+        let evict = self.flows.len() + 1 - self.max_flows + self.max_flows / 8;
+        let mut by_age: Vec<(Duration, FlowKey)> = self
+            .flows
+            .iter()
+            .map(|(key, flow)| (flow.last_seen, *key))
+            .collect();
+        by_age.select_nth_unstable_by_key(evict - 1, |(seen, _)| *seen);
+        for (_, key) in &by_age[..evict] {
+            self.flows.remove(key);
+        }
+        warn!(
+            logger(),
+            "Flow table full at {} flows, evicted the {} least recently seen",
+            self.max_flows,
+            evict
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Flow> {
+        self.flows.values()
+    }
+
+    /// Forgets the cached matches of every flow
+    pub fn clear_matches(&mut self) {
+        self.flows.values_mut().for_each(Flow::clear_matched);
+    }
+}
+
+impl Display for Flow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} {} -> {}",
+            self.proto, self.initiator, self.responder
+        )?;
+        if let Some(state) = self.state {
+            write!(f, " {state:?}")?;
+        }
+        if let Some(sni) = &self.sni {
+            write!(f, " sni {sni}")?;
+        }
+        if let Some(req) = &self.http {
+            let host = req.host.as_deref().unwrap_or("");
+            write!(f, " http {} {host}{}", req.method, req.path)?;
+        }
+        write!(
+            f,
+            ", {}/{} packets, {}/{} bytes",
+            self.packets[0], self.packets[1], self.bytes[0], self.bytes[1]
+        )?;
+        if let [Some(fwd), Some(rev)] = self.last_seq {
+            write!(f, ", last seq {fwd}/{rev}")?;
+        }
+        write!(
+            f,
+            ", {}s",
+            self.last_seen.saturating_sub(self.first_seen).as_secs()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, Flow, FlowTable, TcpState};
+    use crate::{filters::Match, packet_utils::ParsedPacket};
+    use std::time::Duration;
+
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+    const FIN: u8 = 0x01;
+    const RST: u8 = 0x04;
+
+    /// TCP frame between 192.168.0.103:51000 and 93.184.216.34:443, `forward`
+    /// picks the direction
+    fn tcp_frame(forward: bool, flags: u8, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let (src, dst, sport, dport) = if forward {
+            ([192, 168, 0, 103], [93, 184, 216, 34], 51000u16, 443u16)
+        } else {
+            ([93, 184, 216, 34], [192, 168, 0, 103], 443, 51000)
+        };
+        let total = (40 + payload.len()) as u16;
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00, 0x45, 0x00]);
+        frame.extend_from_slice(&total.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0x00, 0x40, 0x06, 0, 0]);
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&sport.to_be_bytes());
+        frame.extend_from_slice(&dport.to_be_bytes());
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xfa, 0xf0, 0, 0, 0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn feed(table: &mut FlowTable, frame: &[u8], secs: u64) -> (Option<TcpState>, Direction) {
+        let packet = ParsedPacket::parse(frame).unwrap();
+        let (flow, dir) = table.track(&packet, Duration::from_secs(secs)).unwrap();
+        (flow.state, dir)
+    }
+
+    fn only(table: &FlowTable) -> &Flow {
+        assert_eq!(table.len(), 1);
+        table.flows.values().next().unwrap()
+    }
+
+    #[test]
+    fn both_directions_share_a_flow() {
+        let mut table = FlowTable::new(64);
+        assert_eq!(
+            feed(&mut table, &tcp_frame(true, SYN, 100, &[]), 0),
+            (Some(TcpState::SynSent), Direction::Forward)
+        );
+        assert_eq!(
+            feed(&mut table, &tcp_frame(false, SYN | ACK, 900, &[]), 0),
+            (Some(TcpState::SynSent), Direction::Reverse)
+        );
+        assert_eq!(
+            feed(&mut table, &tcp_frame(true, ACK, 101, b"GET /"), 1),
+            (Some(TcpState::Established), Direction::Forward)
+        );
+        let flow = only(&table);
+        assert_eq!(flow.packets, [2, 1]);
+        assert_eq!(flow.bytes, [5, 0]);
+        assert_eq!(flow.last_seq, [Some(101), Some(900)]);
+    }
+
+    #[test]
+    fn fins_from_both_sides_close_the_flow() {
+        let mut table = FlowTable::new(64);
+        feed(&mut table, &tcp_frame(true, ACK, 1, &[]), 0);
+        let (state, _) = feed(&mut table, &tcp_frame(true, FIN | ACK, 1, &[]), 0);
+        assert_eq!(state, Some(TcpState::FinWait));
+        let (state, _) = feed(&mut table, &tcp_frame(false, FIN | ACK, 1, &[]), 0);
+        assert_eq!(state, Some(TcpState::Closed));
+    }
+
+    #[test]
+    fn syn_ack_picked_up_first_keeps_the_initiator() {
+        let mut table = FlowTable::new(64);
+        let (_, dir) = feed(&mut table, &tcp_frame(false, SYN | ACK, 1, &[]), 0);
+        assert_eq!(dir, Direction::Reverse);
+        assert_eq!(only(&table).initiator.port(), 51000);
+    }
+
+    #[test]
+    fn syn_after_close_starts_a_new_flow() {
+        let mut table = FlowTable::new(64);
+        feed(&mut table, &tcp_frame(true, ACK, 1, b"x"), 0);
+        feed(&mut table, &tcp_frame(false, RST, 1, &[]), 0);
+        let (state, _) = feed(&mut table, &tcp_frame(true, SYN, 7, &[]), 1);
+        assert_eq!(state, Some(TcpState::SynSent));
+        assert_eq!(only(&table).packets, [1, 0]);
+    }
+
+    #[test]
+    fn idle_flows_expire() {
+        let mut table = FlowTable::new(64);
+        feed(&mut table, &tcp_frame(true, SYN, 1, &[]), 0);
+        assert!(table.expire(Duration::from_secs(30)).is_empty());
+        assert_eq!(table.expire(Duration::from_secs(31)).len(), 1);
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn a_full_table_evicts_the_least_recently_seen_flows() {
+        let mut table = FlowTable::new(16);
+        for port in 0..16u16 {
+            let mut frame = tcp_frame(true, ACK, 1, &[]);
+            frame[34..36].copy_from_slice(&(40000 + port).to_be_bytes());
+            feed(&mut table, &frame, u64::from(port));
+        }
+        assert_eq!(table.len(), 16);
+        // nothing is idle yet, the two oldest flows and one more make room
+        feed(&mut table, &tcp_frame(true, SYN, 1, &[]), 20);
+        assert_eq!(table.len(), 14);
+        let ports: Vec<u16> = table.flows.values().map(|f| f.initiator.port()).collect();
+        assert!(!ports.contains(&40000) && !ports.contains(&40001) && !ports.contains(&40002));
+        assert!(ports.contains(&40003) && ports.contains(&51000));
+    }
+
+    #[test]
+    fn matches_are_cached_per_direction() {
+        let mut table = FlowTable::new(64);
+        let frame = tcp_frame(true, SYN, 1, &[]);
+        let packet = ParsedPacket::parse(&frame).unwrap();
+        let (flow, dir) = table.track(&packet, Duration::ZERO).unwrap();
+        assert_eq!(flow.matched(dir), None);
+        flow.set_matched(dir, Match::Filter(2));
+        assert_eq!(flow.matched(Direction::Forward), Some(Match::Filter(2)));
+        assert_eq!(flow.matched(Direction::Reverse), None);
+        flow.clear_matched();
+        assert_eq!(flow.matched(Direction::Forward), None);
+    }
+}
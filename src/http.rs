@@ -0,0 +1,134 @@
+const METHODS: [&str; 9] = [
+    "GET", "POST", "HEAD", "PUT", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+/// Start of a plaintext HTTP/1.x request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Path and query of the request target
+    pub path: String,
+    /// From the `Host:` header or an absolute target, lowercase and without port
+    pub host: Option<String>,
+}
+
+/// What the first bytes a client sent say about an HTTP request
+#[derive(Debug, PartialEq)]
+pub enum RequestHead {
+    Complete(Request),
+    /// Looks like a request whose headers haven't all arrived yet
+    Incomplete,
+    NotHttp,
+}
+
+/// Parses the request line and headers at the start of a client's TCP stream
+pub fn parse_request(stream: &[u8]) -> RequestHead {
+    let method_len = stream.iter().take(8).position(|b| *b == b' ');
+    let method = &stream[..method_len.unwrap_or(stream.len().min(8))];
+    let known = METHODS.iter().any(|m| match method_len {
+        Some(_) => m.as_bytes() == method,
+        None => m.as_bytes().starts_with(method),
+    });
+    if !known {
+        return RequestHead::NotHttp;
+    }
+    let Some(end) = stream.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return RequestHead::Incomplete;
+    };
+    let Ok(head) = std::str::from_utf8(&stream[..end]) else {
+        return RequestHead::NotHttp;
+    };
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target), Some(version), None) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return RequestHead::NotHttp;
+    };
+    if !version.starts_with("HTTP/1.") {
+        return RequestHead::NotHttp;
+    }
+
+    let mut host = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim());
+    // proxies get absolute targets, their host wins over the header
+    let mut path = target;
+    if let Some(rest) = target
+        .strip_prefix("http://")
+        .or_else(|| target.strip_prefix("https://"))
+    {
+        let (authority, rest_path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        host = Some(authority);
+        path = if rest_path.is_empty() { "/" } else { rest_path };
+    }
+    RequestHead::Complete(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        host: host.map(strip_port).filter(|h| !h.is_empty()),
+    })
+}
+
+fn strip_port(host: &str) -> String {
+    let host = match host.rsplit_once(':') {
+        // IPv6 literals are bracketed, their colons aren't a port
+        Some((name, port)) if !port.contains(']') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    };
+    host.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_request, Request, RequestHead};
+
+    #[test]
+    fn host_and_path_are_extracted() {
+        let head = parse_request(
+            b"GET /admin?x=1 HTTP/1.1\r\nUser-Agent: t\r\nhost: Intranet.Local:8080\r\n\r\n",
+        );
+        assert_eq!(
+            head,
+            RequestHead::Complete(Request {
+                method: "GET".to_string(),
+                path: "/admin?x=1".to_string(),
+                host: Some("intranet.local".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn absolute_targets_carry_the_host() {
+        let RequestHead::Complete(req) = parse_request(b"GET http://example.com HTTP/1.0\r\n\r\n")
+        else {
+            panic!("expected a request");
+        };
+        assert_eq!(req.host.as_deref(), Some("example.com"));
+        assert_eq!(req.path, "/");
+    }
+
+    #[test]
+    fn partial_requests_need_more_bytes() {
+        assert_eq!(parse_request(b"PO"), RequestHead::Incomplete);
+        assert_eq!(
+            parse_request(b"POST /upload HTTP/1.1\r\nHost: a"),
+            RequestHead::Incomplete
+        );
+    }
+
+    #[test]
+    fn other_protocols_are_not_http() {
+        assert_eq!(
+            parse_request(b"SSH-2.0-OpenSSH_9.6\r\n"),
+            RequestHead::NotHttp
+        );
+        assert_eq!(parse_request(b"\x16\x03\x01\x02\x00"), RequestHead::NotHttp);
+        assert_eq!(parse_request(b"GET / SPDY/3\r\n\r\n"), RequestHead::NotHttp);
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    fmt::Debug,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::errors::ConfigError;
+
+/// Inclusive range of addresses of a single IP version, parsed from an
+/// address (`10.0.0.1`), a CIDR block (`10.0.0.0/8`) or a dash range
+/// (`10.0.0.1-10.0.0.50`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpRange {
+    V4(u32, u32),
+    V6(u128, u128),
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Self::V4(ip.into(), ip.into()),
+            IpAddr::V6(ip) => Self::V6(ip.into(), ip.into()),
+        }
+    }
+}
+
+impl TryFrom<&str> for IpRange {
+    type Error = ConfigError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let err = |reason: &str| ConfigError::FailedToParseAsIpAddr(format!("{reason} : {value}"));
+        let parse_ip = |s: &str| s.trim().parse::<IpAddr>().map_err(|e| err(&e.to_string()));
+
+        if let Some((ip, prefix)) = value.split_once('/') {
+            let prefix = prefix
+                .trim()
+                .parse::<u8>()
+                .map_err(|e| err(&e.to_string()))?;
+            return match parse_ip(ip)? {
+                IpAddr::V4(ip) if prefix <= 32 => {
+                    let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                    let start = u32::from(ip) & mask;
+                    Ok(Self::V4(start, start | !mask))
+                }
+                IpAddr::V6(ip) if prefix <= 128 => {
+                    let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                    let start = u128::from(ip) & mask;
+                    Ok(Self::V6(start, start | !mask))
+                }
+                _ => Err(err("invalid prefix length")),
+            };
+        }
+
+        if let Some((start, end)) = value.split_once('-') {
+            let range = match (parse_ip(start)?, parse_ip(end)?) {
+                (IpAddr::V4(start), IpAddr::V4(end)) => Self::V4(start.into(), end.into()),
+                (IpAddr::V6(start), IpAddr::V6(end)) => Self::V6(start.into(), end.into()),
+                _ => return Err(err("range bounds must share an IP version")),
+            };
+            return match range {
+                Self::V4(start, end) if start > end => Err(err("range start is after its end")),
+                Self::V6(start, end) if start > end => Err(err("range start is after its end")),
+                range => Ok(range),
+            };
+        }
+
+        parse_ip(value).map(Self::from)
+    }
+}
+
+/// Set of IP addresses stored as sorted, non-overlapping ranges per IP
+/// version, so a lookup is a binary search no matter how many addresses,
+/// blocks or ranges the set was built from
+#[derive(Clone, Default)]
+pub struct IpSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl FromIterator<IpRange> for IpSet {
+    fn from_iter<T: IntoIterator<Item = IpRange>>(iter: T) -> Self {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for range in iter {
+            match range {
+                IpRange::V4(start, end) => v4.push((start, end)),
+                IpRange::V6(start, end) => v6.push((start, end)),
+            }
+        }
+        Self {
+            v4: merge(v4),
+            v6: merge(v6),
+        }
+    }
+}
+
+impl IpSet {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => lookup(&self.v4, u32::from(*ip)),
+            IpAddr::V6(ip) => lookup(&self.v6, u128::from(*ip)),
+        }
+    }
+    /// Whether every address of `other` is also in the set
+    pub fn covers(&self, other: &IpSet) -> bool {
+        within(&self.v4, &other.v4) && within(&self.v6, &other.v6)
+    }
+    pub fn ranges(&self) -> impl Iterator<Item = (IpAddr, IpAddr)> + '_ {
+        let v4 = self.v4.iter().map(|&(start, end)| {
+            (
+                IpAddr::V4(Ipv4Addr::from(start)),
+                IpAddr::V4(Ipv4Addr::from(end)),
+            )
+        });
+        let v6 = self.v6.iter().map(|&(start, end)| {
+            (
+                IpAddr::V6(Ipv6Addr::from(start)),
+                IpAddr::V6(Ipv6Addr::from(end)),
+            )
+        });
+        v4.chain(v6)
+    }
+}
+
+impl Debug for IpSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.ranges().map(|(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{start}-{end}")
+                }
+            }))
+            .finish()
+    }
+}
+
+/// Sorts the ranges and folds overlapping ones together
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged.shrink_to_fit();
+    merged
+}
+
+fn lookup<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let idx = ranges.partition_point(|&(start, _)| start <= ip);
+    idx > 0 && ranges[idx - 1].1 >= ip
+}
+
+fn within<T: Ord + Copy>(outer: &[(T, T)], inner: &[(T, T)]) -> bool {
+    inner.iter().all(|&(start, end)| {
+        let idx = outer.partition_point(|&(s, _)| s <= start);
+        idx > 0 && outer[idx - 1].1 >= end
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpRange, IpSet};
+    use std::net::IpAddr;
+
+    fn set(items: &[&str]) -> IpSet {
+        items
+            .iter()
+            .map(|i| IpRange::try_from(*i).unwrap())
+            .collect()
+    }
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn single_addresses_match_exactly() {
+        let s = set(&["192.168.0.1", "2001:db8::1"]);
+        assert!(s.contains(&ip("192.168.0.1")));
+        assert!(s.contains(&ip("2001:db8::1")));
+        assert!(!s.contains(&ip("192.168.0.2")));
+        assert!(!s.contains(&ip("2001:db8::2")));
+    }
+    #[test]
+    fn cidr_blocks_match_their_subnet() {
+        let s = set(&["10.0.0.0/8", "2001:db8::/32"]);
+        assert!(s.contains(&ip("10.0.0.0")));
+        assert!(s.contains(&ip("10.255.255.255")));
+        assert!(!s.contains(&ip("11.0.0.0")));
+        assert!(s.contains(&ip("2001:db8:ffff::1")));
+        assert!(!s.contains(&ip("2001:db9::")));
+    }
+    #[test]
+    fn cidr_host_bits_are_masked() {
+        assert_eq!(
+            IpRange::try_from("192.168.1.77/24").unwrap(),
+            IpRange::try_from("192.168.1.0-192.168.1.255").unwrap()
+        );
+    }
+    #[test]
+    fn zero_prefix_matches_everything() {
+        let s = set(&["0.0.0.0/0"]);
+        assert!(s.contains(&ip("255.255.255.255")));
+        assert!(!s.contains(&ip("::1")));
+    }
+    #[test]
+    fn dash_ranges_are_inclusive() {
+        let s = set(&["10.0.0.10-10.0.0.20"]);
+        assert!(s.contains(&ip("10.0.0.10")));
+        assert!(s.contains(&ip("10.0.0.20")));
+        assert!(!s.contains(&ip("10.0.0.9")));
+        assert!(!s.contains(&ip("10.0.0.21")));
+    }
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let s = set(&["10.0.0.0/24", "10.0.0.128-10.0.1.10", "10.0.0.5"]);
+        assert_eq!(s.ranges().count(), 1);
+        assert!(s.contains(&ip("10.0.1.10")));
+        assert!(!s.contains(&ip("10.0.1.11")));
+    }
+    #[test]
+    #[should_panic(expected = "FailedToParseAsIpAddr")]
+    fn panics_on_prefix_too_long() {
+        let _val = IpRange::try_from("10.0.0.0/33").unwrap();
+    }
+    #[test]
+    #[should_panic(expected = "FailedToParseAsIpAddr")]
+    fn panics_on_mixed_version_range() {
+        let _val = IpRange::try_from("10.0.0.1-::1").unwrap();
+    }
+    #[test]
+    #[should_panic(expected = "FailedToParseAsIpAddr")]
+    fn panics_on_reversed_range() {
+        let _val = IpRange::try_from("10.0.0.9-10.0.0.1").unwrap();
+    }
+    #[test]
+    fn sets_cover_their_subsets() {
+        let s = set(&["10.0.0.0/8", "2001:db8::/32"]);
+        assert!(s.covers(&set(&["10.1.2.3", "10.2.0.0-10.2.0.9"])));
+        assert!(!s.covers(&set(&["10.0.0.1", "192.168.0.1"])));
+        assert!(!s.covers(&set(&["2001:db9::1"])));
+        assert!(s.covers(&IpSet::default()));
+    }
+}
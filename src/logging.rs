@@ -1,11 +1,20 @@
 use slog::{o, Drain, LevelFilter, Logger, Record};
 use slog_scope::{set_global_logger, GlobalLoggerGuard};
 use slog_term::{CompactFormat, FullFormat, PlainDecorator, TermDecorator};
+use std::fs::OpenOptions;
 
 use crate::configs::LogConfig;
 
 pub fn init_logger(log_conf: LogConfig) -> GlobalLoggerGuard {
-    let drain = match log_conf.log_file {
+    let file = log_conf.log_file.map(|path| {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap_or_else(|e| panic!("Failed to open log file [{}]: {e}", path.display()))
+    });
+    let drain = match file {
         Some(file) => {
             let decorator = PlainDecorator::new(file);
             let drain = FullFormat::new(decorator).build().fuse();
@@ -18,7 +27,6 @@ pub fn init_logger(log_conf: LogConfig) -> GlobalLoggerGuard {
         }
     };
 
-    println!("log-level: {:?}", log_conf.log_level);
     let logger = match log_conf.log_level {
         Some(l) => {
             let drain = LevelFilter::new(drain, l).fuse();
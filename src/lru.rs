@@ -0,0 +1,126 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    time::Duration,
+};
+
+/// Map bounded to `capacity` entries, evicting the least recently used one
+/// when full. Every entry also carries a deadline after which it's dropped,
+/// times are durations since the epoch like the capture timestamps.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: HashMap<K, Entry<V>>,
+    /// Keys by last use, oldest first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    expires: Duration,
+    used: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns the entry and marks it as used, expired entries are removed
+    pub fn get(&mut self, key: &K, now: Duration) -> Option<&V> {
+        let expired = self.entries.get(key)?.expires <= now;
+        if expired {
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.used);
+        self.order.insert(self.tick, key.clone());
+        entry.used = self.tick;
+        Some(&entry.value)
+    }
+
+    /// Inserts or replaces an entry, returns whether another one had to be
+    /// evicted to make room
+    pub fn insert(&mut self, key: K, value: V, expires: Duration) -> bool {
+        self.remove(&key);
+        let evicted = self.entries.len() >= self.capacity;
+        if evicted {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                expires,
+                used: self.tick,
+            },
+        );
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.used);
+        Some(entry.value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Entries with their deadline, least recently used first
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V, Duration)> {
+        self.order.values().filter_map(|key| {
+            let entry = self.entries.get(key)?;
+            Some((key, &entry.value, entry.expires))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruCache;
+    use std::time::Duration;
+
+    const LATER: Duration = Duration::from_secs(100);
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        let mut cache = LruCache::new(2);
+        assert!(!cache.insert("a", 1, LATER));
+        assert!(!cache.insert("b", 2, LATER));
+        cache.get(&"a", Duration::ZERO);
+        assert!(cache.insert("c", 3, LATER));
+        assert_eq!(cache.get(&"a", Duration::ZERO), Some(&1));
+        assert_eq!(cache.get(&"b", Duration::ZERO), None);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn replacing_an_entry_doesnt_evict() {
+        let mut cache = LruCache::new(1);
+        cache.insert("a", 1, LATER);
+        assert!(!cache.insert("a", 2, LATER));
+        assert_eq!(cache.get(&"a", Duration::ZERO), Some(&2));
+    }
+
+    #[test]
+    fn entries_expire_at_their_deadline() {
+        let mut cache = LruCache::new(4);
+        cache.insert("a", 1, Duration::from_secs(10));
+        assert_eq!(cache.get(&"a", Duration::from_secs(9)), Some(&1));
+        assert_eq!(cache.get(&"a", Duration::from_secs(10)), None);
+        assert_eq!(cache.len(), 0);
+    }
+}
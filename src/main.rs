@@ -1,124 +1,376 @@
 use anyhow::Result;
-use configs::Config;
-use domains::Resolved;
-use filters::Blacklist;
-use packet_utils::{
-    build_rst_packet_from, get_protocol, src_dst_details, Protocol, TcpFlags, TransportProtocol,
+use clap::Parser;
+use cli::{Cli, Command};
+use configs::{Config, Interface};
+use control::{Control, Request};
+use events::EventLog;
+use filters::shadowed;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use packet_utils::{hexdump, LinkType};
+use pcap::{Activated, Capture, PacketHeader};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use slog::{error, info, warn};
+use slog_scope::{logger, GlobalLoggerGuard};
+use sniffer::{capture_filter, Sniffer};
+use std::{
+    io::{self, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use pcap::{Capture, Packet};
-use slog::{debug, info, trace, warn};
-use slog_scope::logger;
 
-use crate::{filters::PacketAction, logging::init_logger, packet_utils::UdpProtocol};
+use crate::logging::init_logger;
 
 // use pretty_env_logger::env_logger::Builder;
 // use log::{info,trace, warn};
 
+mod cli;
 mod configs;
+mod control;
 mod domains;
 mod errors;
+mod events;
+mod expr;
 mod filters;
+mod flows;
+mod http;
+mod ipset;
 mod logging;
+mod lru;
 mod packet_utils;
+mod quota;
+mod reassembly;
+mod resolver;
+mod schedule;
+mod sniffer;
+mod tls;
+
+const CAPTURE_TIMEOUT_MS: i32 = 500;
+/// How often the learned names are saved while running, on top of shutdown
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+/// Editors write a file in several steps, the reload waits for them to settle
+const RELOAD_DELAY: Duration = Duration::from_millis(300);
 
 fn main() -> Result<()> {
-    let config = Config::build()?;
-    let bl = Blacklist::build(&config.filter);
-    let mut domains = Resolved::build();
+    let cli = Cli::parse();
+    match &cli.command {
+        Some(Command::ListInterfaces) => list_interfaces(),
+        Some(Command::CheckConfig) => check_config(&cli),
+        Some(Command::Replay { pcap }) => replay(load_config(&cli)?, pcap),
+        Some(Command::Ctl { socket, request }) => ctl(&cli, socket.as_deref(), request),
+        Some(Command::Run) | None => run(&cli, load_config(&cli)?),
+    }
+}
 
-    let _guard = init_logger(config.log);
+fn load_config(cli: &Cli) -> Result<Config> {
+    let mut config = Config::build(cli.config.as_deref())?;
+    config.apply_cli(cli)?;
+    Ok(config)
+}
 
+fn start_logging(config: &mut Config) -> GlobalLoggerGuard {
+    let guard = init_logger(std::mem::take(&mut config.log));
     info!(logger(), "Starting application");
-    trace!(logger(), "Some trace");
-    debug!(logger(), "some debug");
-    // error!("Failed to resolve DNS of [192.168.0.1]");
-    let fils = &config.filter;
-    fils.iter().for_each(|f| {
-        println!("{f:?}");
+    info!(logger(), "Loaded config:  [{}]", config.path.display());
+    log_filters(config);
+    guard
+}
+
+fn log_filters(config: &Config) {
+    info!(
+        logger(),
+        "Filters in evaluation order, the first match decides:"
+    );
+    config.filter.iter().enumerate().for_each(|(idx, f)| {
+        info!(logger(), "#{} {:?}", idx + 1, f);
     });
+}
 
-    let cap = match config.interface {
-        configs::Interface::Lookup => pcap::Device::lookup().unwrap().unwrap(),
-        configs::Interface::Custom(dev) => pcap::Device::from(dev.as_str()),
+/// Loads the config file again with the same command line overrides, the
+/// running filters are only replaced if the whole config is valid
+fn reload_config(cli: &Cli, path: &Path) -> Option<Config> {
+    let config = Config::build(Some(path)).and_then(|mut config| {
+        config.apply_cli(cli)?;
+        Ok(config)
+    });
+    match config {
+        Ok(config) => {
+            info!(logger(), "Reloaded config:  [{}]", path.display());
+            log_filters(&config);
+            Some(config)
+        }
+        Err(e) => {
+            error!(
+                logger(),
+                "Failed to reload config [{}], keeping the current filters: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Raises `flag` whenever the config file is written or replaced. The
+/// directory is watched since editors often save by renaming a new file
+/// over the old one.
+fn watch_config(path: &Path, flag: Arc<AtomicBool>) -> notify::Result<RecommendedWatcher> {
+    let name = path.file_name().map(|n| n.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        let ours = event
+            .paths
+            .iter()
+            .any(|p| p.file_name().map(|n| n.to_os_string()) == name);
+        if ours && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            flag.store(true, Ordering::Relaxed);
+        }
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
 
-    // let cap = pcap::Device::lookup()?.unwrap();
-    info!(logger(), "Sniffing on interface:  [{}]", cap.name);
-    let cap = Capture::from_device(cap)
-        .unwrap()
+/// Opens the event log if the config asks for one
+fn open_events(config: &Config, interface: Option<String>) -> Result<Option<EventLog>> {
+    let Some(path) = &config.events else {
+        return Ok(None);
+    };
+    let events = EventLog::open(path, interface)
+        .map_err(|e| anyhow::anyhow!("Failed to open event log [{}]: {}", path.display(), e))?;
+    info!(logger(), "Event log:  [{}]", path.display());
+    Ok(Some(events))
+}
+
+/// Sniffs the configured interface and injects resets for matching
+/// connections, reloading the filters on SIGHUP or when the config changes
+fn run(cli: &Cli, mut config: Config) -> Result<()> {
+    let _guard = start_logging(&mut config);
+
+    let dev = match &config.interface {
+        Interface::Lookup => pcap::Device::lookup()?
+            .ok_or_else(|| anyhow::anyhow!("No interface found to sniff on"))?,
+        Interface::Custom(dev) => pcap::Device::from(dev.as_str()),
+    };
+    info!(logger(), "Sniffing on interface:  [{}]", dev.name);
+    let mut events = open_events(&config, Some(dev.name.clone()))?;
+    let mut cap = Capture::from_device(dev)?
         .immediate_mode(true)
-        .promisc(true);
+        .promisc(true)
+        // wakes the loop up on quiet networks to notice signals
+        .timeout(CAPTURE_TIMEOUT_MS)
+        .open()?;
+    cap.filter(&capture_filter(), true)?;
 
-    //open and filter
-    let mut cap = cap.open().unwrap();
-    let filter_tcp_syn = "tcp[13] & 2!=0";
-    let filter_tcp_ack = "tcp[13] & 16 != 0";
-    let filter_dns_rsp = "udp src port 53 and udp[2] & 0x80 != 0";
-    let filter = format!("{filter_tcp_syn} or {filter_tcp_ack} or ({filter_dns_rsp})");
-    cap.filter(&filter, true).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))?;
+    }
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
+    // dropping the watcher stops it
+    let _watcher = watch_config(&config.path, Arc::clone(&reload))
+        .map_err(|e| warn!(logger(), "Not watching the config file for changes: {}", e))
+        .ok();
 
-    while let Ok(packet) = cap.next_packet() {
-        let (src, src_port, src_mac, dst, dst_port, dst_mac) = src_dst_details(&packet);
-        let src = std::net::IpAddr::V4(src);
-        let dst = std::net::IpAddr::V4(dst);
-        let arg = (src, src_port, src_mac, dst, dst_port, dst_mac);
-
-        match bl.get_packet_action(arg, &domains) {
-            PacketAction::Ignore => {
-                continue;
+    let mut control = match &config.control_socket {
+        Some(path) => {
+            let requests = control::serve(path).map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to listen on control socket [{}]: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+            info!(logger(), "Control socket:  [{}]", path.display());
+            let control = Control::new(&config, cli.dry_run, Arc::clone(&reload));
+            Some((requests, control))
+        }
+        None => None,
+    };
+
+    let mut sniffer = Sniffer::build(&config, link_type(&cap)?);
+    let mut last_save = Instant::now();
+    let mut reload_at = None;
+    while !shutdown.load(Ordering::Relaxed) {
+        if last_save.elapsed() >= STATE_SAVE_INTERVAL {
+            sniffer.save_state();
+            last_save = Instant::now();
+        }
+        if reload.swap(false, Ordering::Relaxed) {
+            reload_at = Some(Instant::now() + RELOAD_DELAY);
+        }
+        if reload_at.is_some_and(|at| Instant::now() >= at) {
+            reload_at = None;
+            if let Some(new) = reload_config(cli, &config.path) {
+                sniffer.reload(&new);
+                if let Some((_, control)) = &mut control {
+                    control.reload(&new);
+                }
             }
-            PacketAction::Monitor => {
-                warn!(
-                    logger(),
-                    "detected connection src:[{}] -> dst:[{}]", src, dst
-                );
-                continue;
+        }
+        if let Some((requests, control)) = &mut control {
+            for (request, reply) in requests.try_iter() {
+                // the client may have hung up already
+                let _ = reply.send(control.handle(request, &mut sniffer));
             }
-            PacketAction::Reset => {}
-            PacketAction::SynReset => {
-                unimplemented!()
+        }
+        let packet = match cap.next_packet() {
+            Ok(packet) => packet,
+            Err(pcap::Error::TimeoutExpired) => continue,
+            Err(e) => {
+                error!(logger(), "Capture failed: {}", e);
+                break;
             }
         };
+        let ts = timestamp(packet.header);
+        let Ok(decision) = sniffer.handle(packet.data, ts) else {
+            continue;
+        };
+        let mut sent = false;
+        if let Some(rst) = &decision.rst {
+            if decision.dry_run {
+                info!(logger(), "dry-run, not sending: {}", decision);
+                info!(logger(), "rst packet:\n{}", hexdump(rst));
+            } else {
+                match cap.sendpacket(rst.as_slice()) {
+                    Ok(()) => sent = true,
+                    Err(e) => warn!(logger(), "Failed to send rst: {}", e),
+                }
+            }
+        }
+        if let Some(events) = &mut events {
+            if decision.is_event() {
+                events.record(&decision, ts, sent);
+            }
+        }
+    }
+    info!(logger(), "Shutting down");
+    sniffer.save_state();
+    if let Some(path) = &config.control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
 
-        let proto = get_protocol(&packet);
-        dbg!(proto.clone());
-        match proto {
-            Protocol::Ipv4(transport) => {
-                match transport {
-                    TransportProtocol::TCP(tcp) => {
-                        match tcp {
-                            TcpFlags::SynAck(syn, ack) => {
-                                if ack || syn {
-                                    let rst = build_rst_packet_from(&packet, syn);
-                                    let rp = Packet {
-                                        header: packet.header,
-                                        data: &rst,
-                                    };
-                                    println!("sending packet:");
-                                    src_dst_details(&rp);
-                                    // tcp_details(&rp);
-                                    // println!("===========");
-                                    if let Err(e) = cap.sendpacket(rst) {
-                                        eprintln!("send-error: {e:?}");
-                                    }
-                                }
-                            }
-                            TcpFlags::Other => {}
-                        }
+/// Runs the filters over a saved capture and prints the decision taken for
+/// every packet, nothing is ever sent
+fn replay(mut config: Config, path: &Path) -> Result<()> {
+    let _guard = start_logging(&mut config);
+    info!(logger(), "Replaying capture:  [{}]", path.display());
+    let mut cap = Capture::from_file(path)?;
+    cap.filter(&capture_filter(), true)?;
+
+    let mut events = open_events(&config, None)?;
+    // keep the event log on stdout parseable
+    let mut report: Box<dyn Write> = if config.events.as_deref() == Some(Path::new("-")) {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+    let mut sniffer = Sniffer::build(&config, link_type(&cap)?);
+    let mut matches = vec![0u64; config.filter.len()];
+    let (mut packets, mut resets) = (0u64, 0u64);
+    while let Ok(packet) = cap.next_packet() {
+        packets += 1;
+        let ts = timestamp(packet.header);
+        match sniffer.handle(packet.data, ts) {
+            Ok(decision) => {
+                if let Some(events) = &mut events {
+                    if decision.is_event() {
+                        events.record(&decision, ts, false);
                     }
-                    TransportProtocol::UDP(u) => match u {
-                        UdpProtocol::DNS => domains.update_from_dns(packet.data),
-                        _ => {}
-                    },
-                    _ => {}
                 }
+                if let Some(idx) = decision.filter {
+                    matches[idx] += 1;
+                }
+                if decision.rst.is_some() {
+                    resets += 1;
+                }
+                writeln!(report, "packet {packets}: {decision}")?;
             }
-            Protocol::Ipv6(_) => {}
+            Err(e) => writeln!(report, "packet {packets}: malformed, {e}")?,
         }
     }
-    let fils = &config.filter;
-    fils.iter().for_each(|f| {
-        println!("{f:?}");
+
+    writeln!(
+        report,
+        "{packets} packets, {} malformed, {resets} resets",
+        sniffer.malformed()
+    )?;
+    writeln!(report, "DNS cache: {}", sniffer.dns_stats())?;
+    for (idx, (f, count)) in config.filter.iter().zip(matches).enumerate() {
+        writeln!(report, "filter #{}: {count} matches {f:?}", idx + 1)?;
+    }
+    Ok(())
+}
+
+fn link_type<T: Activated + ?Sized>(cap: &Capture<T>) -> Result<LinkType> {
+    let dlt = cap.get_datalink();
+    LinkType::from_dlt(dlt.0).ok_or_else(|| anyhow::anyhow!("Unsupported link type {:?}", dlt))
+}
+
+/// Capture time of a packet, as a duration since the epoch
+fn timestamp(header: &PacketHeader) -> Duration {
+    Duration::new(header.ts.tv_sec as u64, header.ts.tv_usec as u32 * 1000)
+}
+
+fn check_config(cli: &Cli) -> Result<()> {
+    let config = load_config(cli)?;
+    println!(
+        "Config [{}] is valid, {} filters:",
+        config.path.display(),
+        config.filter.len()
+    );
+    config.filter.iter().enumerate().for_each(|(idx, f)| {
+        println!("#{} {f:?}", idx + 1);
     });
+    for (idx, by) in shadowed(&config.filter) {
+        println!(
+            "warning: filter #{} (rule {}) never matches, filter #{} (rule {}) matches everything it would first",
+            idx + 1,
+            config.filter[idx].rule,
+            by + 1,
+            config.filter[by].rule
+        );
+    }
+    Ok(())
+}
+
+/// Sends a request to a running instance and prints its response
+fn ctl(cli: &Cli, socket: Option<&Path>, request: &Request) -> Result<()> {
+    let socket = match socket {
+        Some(path) => path.to_path_buf(),
+        None => load_config(cli)?.control_socket.ok_or_else(|| {
+            anyhow::anyhow!("No control socket, set `socket` in `[control]` or pass --socket")
+        })?,
+    };
+    let response = control::send(&socket, request)
+        .map_err(|e| anyhow::anyhow!("Control socket [{}]: {}", socket.display(), e))?;
+    if response["ok"] != true {
+        anyhow::bail!("{}", response["error"].as_str().unwrap_or("request failed"));
+    }
+    println!("{}", serde_json::to_string_pretty(&response["result"])?);
+    Ok(())
+}
+
+fn list_interfaces() -> Result<()> {
+    for dev in pcap::Device::list()? {
+        let addresses: Vec<String> = dev.addresses.iter().map(|a| a.addr.to_string()).collect();
+        println!(
+            "{} {} [{}]",
+            dev.name,
+            dev.desc.as_deref().unwrap_or(""),
+            addresses.join(", ")
+        );
+    }
     Ok(())
 }
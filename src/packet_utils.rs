@@ -1,179 +1,500 @@
 #![allow(unused)]
-use pcap::Packet;
 use rayon::{prelude::ParallelIterator, slice::ParallelSlice};
 use std::{
-    net::Ipv4Addr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     sync::atomic::{AtomicU32, AtomicU64},
 };
 
+use crate::errors::PacketError;
+
+const ETH_HEADER_LEN: usize = 14;
+const VLAN_TAG_LEN: usize = 4;
+const SLL_HEADER_LEN: usize = 16;
+const SLL2_HEADER_LEN: usize = 20;
+const IPV4_MIN_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_MIN_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+const ETHER_TYPE_IPV6: u16 = 0x86dd;
+const ETHER_TYPE_VLAN: u16 = 0x8100;
+const ETHER_TYPE_QINQ: u16 = 0x88a8;
+/// ARPHRD_ETHER, the only hardware type whose address is a MAC
+const ARPHRD_ETHER: u16 = 1;
+
+const TCP_FIN: u8 = 0b0000_0001;
+const TCP_SYN: u8 = 0b0000_0010;
+const TCP_RST: u8 = 0b0000_0100;
+const TCP_ACK: u8 = 0b0001_0000;
+
+/// Link layer of a capture, as reported by its data link type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    Ethernet,
+    /// Linux cooked capture, used by the `any` interface
+    LinuxSll,
+    LinuxSll2,
+}
+
+impl LinkType {
+    /// Maps a pcap `DLT_*` value to a supported link type
+    pub fn from_dlt(dlt: i32) -> Option<Self> {
+        match dlt {
+            1 => Some(Self::Ethernet),
+            113 => Some(Self::LinuxSll),
+            276 => Some(Self::LinuxSll2),
+            _ => None,
+        }
+    }
+}
+
+/// A captured frame whose link, IP and transport headers have been length
+/// checked once, so the accessors never index out of bounds.
+/// Payloads are borrowed from the captured buffer.
+#[derive(Debug, Clone)]
+pub struct ParsedPacket<'a> {
+    data: &'a [u8],
+    link: LinkHeader<'a>,
+    ip: IpHeader,
+    transport: Transport<'a>,
+}
+
 #[derive(Debug, Clone)]
-pub enum Protocol {
-    Ipv4(TransportProtocol),
-    Ipv6(TransportProtocol),
+pub struct LinkHeader<'a> {
+    /// Missing on cooked captures, which only record the sender's address
+    pub dst_mac: Option<&'a [u8; 6]>,
+    pub src_mac: Option<&'a [u8; 6]>,
+    /// 802.1Q and 802.1ad tags between the MACs and the ether type, 4 bytes each
+    pub vlan_tags: &'a [u8],
+    pub ether_type: u16,
+    /// Length of the whole link header, where the IP header starts
+    pub len: usize,
 }
 
 #[derive(Debug, Clone)]
-pub enum TransportProtocol {
-    TCP(TcpFlags),
-    UDP(UdpProtocol),
-    Other,
+pub struct IpHeader {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// IP protocol number of the transport header, after any IPv6 extension headers
+    pub protocol: u8,
+    /// A fragment other than the first, its payload carries no transport header
+    pub later_fragment: bool,
 }
 
 #[derive(Debug, Clone)]
-pub enum TcpFlags {
-    SynAck(bool, bool),
-    Other,
+pub enum Transport<'a> {
+    Tcp(TcpSegment<'a>),
+    Udp(UdpDatagram<'a>),
+    Other(u8),
 }
 
 #[derive(Debug, Clone)]
-pub enum UdpProtocol {
-    DNS,
-    Other,
+pub struct TcpSegment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq_num: u32,
+    pub ack_num: u32,
+    pub flags: u8,
+    pub window_size: u16,
+    pub payload: &'a [u8],
 }
 
-pub fn is_ipv4(packet: &Packet) -> bool {
-    let ip_header = &packet.data[14..34];
-    let ip_version = (ip_header[0] & 0b1111_0000) >> 4;
-    ip_version == 4
+#[derive(Debug, Clone)]
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> ParsedPacket<'a> {
+    /// Parses an ethernet frame
+    pub fn parse(data: &'a [u8]) -> Result<Self, PacketError> {
+        Self::parse_link(data, LinkType::Ethernet)
+    }
+    pub fn parse_link(data: &'a [u8], link_type: LinkType) -> Result<Self, PacketError> {
+        let link = match link_type {
+            LinkType::Ethernet => LinkHeader::parse_ethernet(data)?,
+            LinkType::LinuxSll => LinkHeader::parse_sll(data)?,
+            LinkType::LinuxSll2 => LinkHeader::parse_sll2(data)?,
+        };
+        let (ip, transport_idx, ip_end) = match link.ether_type {
+            ETHER_TYPE_IPV4 => parse_ipv4(data, link.len)?,
+            ETHER_TYPE_IPV6 => parse_ipv6(data, link.len)?,
+            t => return Err(PacketError::UnsupportedEtherType(t)),
+        };
+        let segment = &data[transport_idx..ip_end];
+        let transport = match ip.protocol {
+            _ if ip.later_fragment => Transport::Other(ip.protocol),
+            6 => Transport::Tcp(TcpSegment::parse(segment)?),
+            17 => Transport::Udp(UdpDatagram::parse(segment)?),
+            p => Transport::Other(p),
+        };
+        Ok(Self {
+            data,
+            link,
+            ip,
+            transport,
+        })
+    }
+    /// The whole captured frame
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+    pub fn link(&self) -> &LinkHeader<'a> {
+        &self.link
+    }
+    pub fn ip(&self) -> &IpHeader {
+        &self.ip
+    }
+    pub fn transport(&self) -> &Transport<'a> {
+        &self.transport
+    }
+    pub fn tcp(&self) -> Option<&TcpSegment<'a>> {
+        match &self.transport {
+            Transport::Tcp(tcp) => Some(tcp),
+            _ => None,
+        }
+    }
+    pub fn udp(&self) -> Option<&UdpDatagram<'a>> {
+        match &self.transport {
+            Transport::Udp(udp) => Some(udp),
+            _ => None,
+        }
+    }
+    /// TCP or UDP payload, empty for other protocols
+    pub fn payload(&self) -> &'a [u8] {
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.payload,
+            Transport::Udp(udp) => udp.payload,
+            Transport::Other(_) => &[],
+        }
+    }
+    /// Source and destination ports for TCP and UDP packets
+    pub fn ports(&self) -> Option<(u16, u16)> {
+        match &self.transport {
+            Transport::Tcp(tcp) => Some((tcp.src_port, tcp.dst_port)),
+            Transport::Udp(udp) => Some((udp.src_port, udp.dst_port)),
+            Transport::Other(_) => None,
+        }
+    }
 }
 
-pub fn syn_ack_flags(tcp_header: &[u8]) -> (bool, bool) {
-    // let tcp_header = &packet.data[34..54];
-    let flags = tcp_header[13];
-    let ack = (flags & 0b0001_0000) == 16;
-    let syn = (flags & 0b0000_0010) == 2;
-    (syn, ack)
+impl<'a> LinkHeader<'a> {
+    fn parse_ethernet(data: &'a [u8]) -> Result<Self, PacketError> {
+        let header = data
+            .get(..ETH_HEADER_LEN)
+            .ok_or(PacketError::Truncated("ethernet"))?;
+        // skip any stack of VLAN tags to reach the real ether type
+        let mut type_idx = 12;
+        loop {
+            let ether_type = data
+                .get(type_idx..type_idx + 2)
+                .map(|t| u16::from_be_bytes([t[0], t[1]]))
+                .ok_or(PacketError::Truncated("VLAN"))?;
+            if let ETHER_TYPE_VLAN | ETHER_TYPE_QINQ = ether_type {
+                type_idx += VLAN_TAG_LEN;
+                continue;
+            }
+            return Ok(Self {
+                dst_mac: Some(header[0..6].try_into().unwrap()),
+                src_mac: Some(header[6..12].try_into().unwrap()),
+                vlan_tags: &data[12..type_idx],
+                ether_type,
+                len: type_idx + 2,
+            });
+        }
+    }
+
+    fn parse_sll(data: &'a [u8]) -> Result<Self, PacketError> {
+        let header = data
+            .get(..SLL_HEADER_LEN)
+            .ok_or(PacketError::Truncated("SLL"))?;
+        let hw_type = u16::from_be_bytes([header[2], header[3]]);
+        let addr_len = u16::from_be_bytes([header[4], header[5]]);
+        Ok(Self {
+            dst_mac: None,
+            src_mac: sll_mac(hw_type, addr_len.into(), &header[6..14]),
+            vlan_tags: &[],
+            ether_type: u16::from_be_bytes([header[14], header[15]]),
+            len: SLL_HEADER_LEN,
+        })
+    }
+
+    fn parse_sll2(data: &'a [u8]) -> Result<Self, PacketError> {
+        let header = data
+            .get(..SLL2_HEADER_LEN)
+            .ok_or(PacketError::Truncated("SLL2"))?;
+        let hw_type = u16::from_be_bytes([header[8], header[9]]);
+        Ok(Self {
+            dst_mac: None,
+            src_mac: sll_mac(hw_type, header[11].into(), &header[12..20]),
+            vlan_tags: &[],
+            ether_type: u16::from_be_bytes([header[0], header[1]]),
+            len: SLL2_HEADER_LEN,
+        })
+    }
 }
 
-fn tcp_header_idx(packet: &Packet) -> u8 {
-    let ihl = &packet[14] & 0b0000_1111;
+/// The sender's MAC from the address field of a cooked capture header
+fn sll_mac(hw_type: u16, addr_len: usize, addr: &[u8]) -> Option<&[u8; 6]> {
+    if hw_type != ARPHRD_ETHER || addr_len != 6 {
+        return None;
+    }
+    addr[..6].try_into().ok()
+}
 
-    14 + ihl * 4
+/// Parses the IPv4 header starting at `idx`
+/// # Returns
+/// A tuple with
+/// * the parsed header
+/// * index of the transport header
+/// * end of the IP packet, ethernet padding after it is dropped
+fn parse_ipv4(data: &[u8], idx: usize) -> Result<(IpHeader, usize, usize), PacketError> {
+    let header = data
+        .get(idx..idx + IPV4_MIN_HEADER_LEN)
+        .ok_or(PacketError::Truncated("IPv4"))?;
+    let version = header[0] >> 4;
+    if version != 4 {
+        return Err(PacketError::IpVersionMismatch(version));
+    }
+    let header_len = usize::from(header[0] & 0b0000_1111) * 4;
+    let total_len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    if header_len < IPV4_MIN_HEADER_LEN || total_len < header_len {
+        return Err(PacketError::InvalidHeaderLength("IPv4"));
+    }
+    if data.len() < idx + header_len {
+        return Err(PacketError::Truncated("IPv4"));
+    }
+    let src: [u8; 4] = header[12..16].try_into().unwrap();
+    let dst: [u8; 4] = header[16..20].try_into().unwrap();
+    let fragment_offset = u16::from_be_bytes([header[6], header[7]]) & 0x1fff;
+    let ip = IpHeader {
+        src: IpAddr::V4(Ipv4Addr::from(src)),
+        dst: IpAddr::V4(Ipv4Addr::from(dst)),
+        protocol: header[9],
+        later_fragment: fragment_offset != 0,
+    };
+    Ok((ip, idx + header_len, data.len().min(idx + total_len)))
 }
 
-pub fn get_protocol(packet: &Packet) -> Protocol {
-    let is_ipv4 = is_ipv4(packet);
-    if is_ipv4 {
-        let protocol = &packet.data[23];
-        match protocol {
-            6 => {
-                let ix = tcp_header_idx(packet);
-                let (syn, ack) = syn_ack_flags(&packet.data[ix.into()..]);
-                let transport = if syn || ack {
-                    TcpFlags::SynAck(syn, ack)
-                } else {
-                    TcpFlags::Other
-                };
-                Protocol::Ipv4(TransportProtocol::TCP(transport))
+/// Parses the IPv6 header starting at `idx`, walking the extension header
+/// chain until it reaches a header that isn't an extension header
+fn parse_ipv6(data: &[u8], idx: usize) -> Result<(IpHeader, usize, usize), PacketError> {
+    let header = data
+        .get(idx..idx + IPV6_HEADER_LEN)
+        .ok_or(PacketError::Truncated("IPv6"))?;
+    let version = header[0] >> 4;
+    if version != 6 {
+        return Err(PacketError::IpVersionMismatch(version));
+    }
+    let payload_len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+    let ip_end = data.len().min(idx + IPV6_HEADER_LEN + payload_len);
+
+    let mut next_header = header[6];
+    let mut ext_idx = idx + IPV6_HEADER_LEN;
+    let mut later_fragment = false;
+    loop {
+        let ext = data
+            .get(ext_idx..ext_idx + 2)
+            .filter(|_| ext_idx + 2 <= ip_end);
+        let len = match (next_header, ext) {
+            // hop-by-hop, routing and destination options
+            (0 | 43 | 60, Some(ext)) => (usize::from(ext[1]) + 1) * 8,
+            // fragment, the offset is in the two bytes after the first two
+            (44, Some(_)) => {
+                let offset = data
+                    .get(ext_idx + 2..ext_idx + 4)
+                    .ok_or(PacketError::Truncated("IPv6"))?;
+                later_fragment |= u16::from_be_bytes([offset[0], offset[1]]) >> 3 != 0;
+                8
             }
-            17 => Protocol::Ipv4(TransportProtocol::UDP(UdpProtocol::DNS)),
-            _ => Protocol::Ipv4(TransportProtocol::Other),
+            // authentication header
+            (51, Some(ext)) => (usize::from(ext[1]) + 2) * 4,
+            (0 | 43 | 60 | 44 | 51, None) => return Err(PacketError::Truncated("IPv6")),
+            _ => break,
+        };
+        next_header = data[ext_idx];
+        ext_idx += len;
+    }
+    if ext_idx > ip_end {
+        return Err(PacketError::Truncated("IPv6"));
+    }
+
+    let src: [u8; 16] = header[8..24].try_into().unwrap();
+    let dst: [u8; 16] = header[24..40].try_into().unwrap();
+    let ip = IpHeader {
+        src: IpAddr::V6(Ipv6Addr::from(src)),
+        dst: IpAddr::V6(Ipv6Addr::from(dst)),
+        protocol: next_header,
+        later_fragment,
+    };
+    Ok((ip, ext_idx, ip_end))
+}
+
+impl<'a> TcpSegment<'a> {
+    fn parse(segment: &'a [u8]) -> Result<Self, PacketError> {
+        let header = segment
+            .get(..TCP_MIN_HEADER_LEN)
+            .ok_or(PacketError::Truncated("TCP"))?;
+        let header_len = usize::from(header[12] >> 4) * 4;
+        if header_len < TCP_MIN_HEADER_LEN {
+            return Err(PacketError::InvalidHeaderLength("TCP"));
         }
-    } else {
-        // ipv6 logic
-        unimplemented!()
+        let payload = segment
+            .get(header_len..)
+            .ok_or(PacketError::Truncated("TCP"))?;
+        Ok(Self {
+            src_port: u16::from_be_bytes([header[0], header[1]]),
+            dst_port: u16::from_be_bytes([header[2], header[3]]),
+            seq_num: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            ack_num: u32::from_be_bytes([header[8], header[9], header[10], header[11]]),
+            flags: header[13],
+            window_size: u16::from_be_bytes([header[14], header[15]]),
+            payload,
+        })
+    }
+    pub fn is_syn(&self) -> bool {
+        self.flags & TCP_SYN != 0
+    }
+    pub fn is_ack(&self) -> bool {
+        self.flags & TCP_ACK != 0
+    }
+    pub fn is_rst(&self) -> bool {
+        self.flags & TCP_RST != 0
+    }
+    pub fn is_fin(&self) -> bool {
+        self.flags & TCP_FIN != 0
+    }
+}
+
+impl<'a> UdpDatagram<'a> {
+    fn parse(datagram: &'a [u8]) -> Result<Self, PacketError> {
+        let header = datagram
+            .get(..UDP_HEADER_LEN)
+            .ok_or(PacketError::Truncated("UDP"))?;
+        Ok(Self {
+            src_port: u16::from_be_bytes([header[0], header[1]]),
+            dst_port: u16::from_be_bytes([header[2], header[3]]),
+            payload: &datagram[UDP_HEADER_LEN..],
+        })
     }
 }
 
-// pub fn ack_enabled(packet: &Packet) -> bool {
-//     let tcp_header = &packet.data[34..54];
-//     let flags = tcp_header[13];
-//     let ack = (flags & 0b00010000) == 16;
-//     println!("checking ack : {:#010b} evaluated {ack}", flags);
-//     ack
-// }
-
-pub fn build_rst_packet_from(packet: &Packet, is_syn: bool) -> Vec<u8> {
-    let (src_ip, src_port, src_mac, dst_ip, dst_port, dst_mac) = src_dst_details(packet);
-    let (seq_num, ack_num, window_size) = tcp_details(packet);
-
-    let mut pkt = Vec::with_capacity(54);
-    pkt.extend_from_slice(src_mac);
-    pkt.extend_from_slice(dst_mac);
-    pkt.extend_from_slice(&[0x08, 0x00]);
-
-    // IP header
-    pkt.extend_from_slice(&[
-        0x45, // IP version & header length
-        0x00, // DSCP and ECN
-        0x00, 0x28, // Total lenght
-        0x06, 0x50, // Identification - dontt forget to set this to a unique value later !!!
-        0x40, 0x00, // Flags & fragment offset
-        0x3c, 0x06, // TTL adn protocol(TCP)
-        0x00, 0x00, // temporary Header checksum
-    ]);
-    pkt.extend_from_slice(&dst_ip.octets());
-    pkt.extend_from_slice(&src_ip.octets());
-
-    let ip_checksum = checksum(&pkt[14..34]);
-    pkt[24..26].copy_from_slice(&ip_checksum.to_be_bytes());
+/// Builds a RST segment addressed to the sender of `packet`, `None` if the
+/// packet isn't TCP or was captured without both MACs.
+///
+/// A bare SYN is answered with RST+ACK acknowledging the initiator's ISN, which
+/// is the only reset a host in SYN-SENT will accept. Any other segment gets a
+/// plain RST using the sequence number the sender expects from its peer.
+pub fn build_rst_packet_from(packet: &ParsedPacket) -> Option<Vec<u8>> {
+    let tcp = packet.tcp()?;
+    let link = packet.link();
+    let ip = packet.ip();
+
+    let mut pkt = Vec::with_capacity(74 + link.vlan_tags.len());
+    pkt.extend_from_slice(link.src_mac?);
+    pkt.extend_from_slice(link.dst_mac?);
+    // the reply goes back on the same VLAN
+    pkt.extend_from_slice(link.vlan_tags);
+
+    match (ip.src, ip.dst) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            pkt.extend_from_slice(&ETHER_TYPE_IPV4.to_be_bytes());
+            let ip_idx = pkt.len();
+            pkt.extend_from_slice(&[
+                0x45, // IP version & header length
+                0x00, // DSCP and ECN
+                0x00, 0x28, // Total lenght
+                0x06,
+                0x50, // Identification - dontt forget to set this to a unique value later !!!
+                0x40, 0x00, // Flags & fragment offset
+                0x3c, 0x06, // TTL adn protocol(TCP)
+                0x00, 0x00, // temporary Header checksum
+            ]);
+            pkt.extend_from_slice(&dst_ip.octets());
+            pkt.extend_from_slice(&src_ip.octets());
+
+            let ip_checksum = checksum(&pkt[ip_idx..ip_idx + 20]);
+            pkt[ip_idx + 10..ip_idx + 12].copy_from_slice(&ip_checksum.to_be_bytes());
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            pkt.extend_from_slice(&ETHER_TYPE_IPV6.to_be_bytes());
+            pkt.extend_from_slice(&[
+                0x60, 0x00, 0x00, 0x00, // version, traffic class and flow label
+                0x00, 0x14, // payload length
+                0x06, // next header (TCP)
+                0x40, // hop limit
+            ]);
+            pkt.extend_from_slice(&dst_ip.octets());
+            pkt.extend_from_slice(&src_ip.octets());
+        }
+        _ => unreachable!("source and destination of a packet share an IP version"),
+    }
 
     // TCP section
-    pkt.extend_from_slice(&dst_port.to_be_bytes());
-    pkt.extend_from_slice(&src_port.to_be_bytes());
-    // pkt.extend_from_slice(&(seq_num + payload_len as u32 + 1).to_be_bytes());
-    let rst_seq_num = &(if is_syn { seq_num + 1 } else { ack_num }).to_be_bytes();
-    pkt.extend_from_slice(rst_seq_num); // seq num
-                                        // pkt.extend_from_slice(&(ack_num + packet.len() as u32).to_be_bytes());
-    pkt.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // ack 0
-                                                      // pkt.extend_from_slice(&[0x50, 0x00]); // data offset and reserved
-                                                      // pkt.extend_from_slice(&[0b00010100]); // data offset and reserved
+    let rst_tcp_idx = pkt.len();
+    pkt.extend_from_slice(&tcp.dst_port.to_be_bytes());
+    pkt.extend_from_slice(&tcp.src_port.to_be_bytes());
+    let (rst_seq_num, rst_ack_num, flags) = match (tcp.is_syn(), tcp.is_ack()) {
+        (true, false) => (0, tcp.seq_num.wrapping_add(1), TCP_RST | TCP_ACK),
+        _ => (tcp.ack_num, 0, TCP_RST),
+    };
+    pkt.extend_from_slice(&rst_seq_num.to_be_bytes()); // seq num
+    pkt.extend_from_slice(&rst_ack_num.to_be_bytes()); // ack num
     pkt.extend_from_slice(&[0x50]); // data offset and reserved
-    pkt.extend_from_slice(&[0b0000_0100]); // flag
+    pkt.extend_from_slice(&[flags]); // flag
 
-    pkt.extend_from_slice(&window_size.to_be_bytes());
+    pkt.extend_from_slice(&tcp.window_size.to_be_bytes());
     pkt.extend_from_slice(&[0x00, 0x00]); // initial tcp checksum
     pkt.extend_from_slice(&[0x00, 0x00]); // urgency
 
-    let mut pseudo_ip_header: Vec<u8> = Vec::with_capacity(12);
-    pseudo_ip_header.extend_from_slice(&src_ip.octets());
-    pseudo_ip_header.extend_from_slice(&dst_ip.octets());
-    pseudo_ip_header.extend_from_slice(&[0x00]); //fixed 8 bit
-    pseudo_ip_header.extend_from_slice(&[0x06]); //protocol field
-    pseudo_ip_header.extend_from_slice(&20u16.to_be_bytes()); //TCP segment length
-    pseudo_ip_header.extend_from_slice(&pkt[34..]);
-    pseudo_ip_header[28] = 0;
-    pseudo_ip_header[29] = 0;
-    println!("rst packet");
-    for bytes in &pseudo_ip_header {
-        print!("{bytes:02X} ");
-    }
+    let mut pseudo_ip_header = pseudo_header(ip.src, ip.dst, 20);
+    pseudo_ip_header.extend_from_slice(&pkt[rst_tcp_idx..]);
 
     let tcp_checksum = checksum(&pseudo_ip_header);
-    println!("calculated rst checksum: {tcp_checksum:X?}");
-    pkt[50..52].copy_from_slice(&tcp_checksum.to_be_bytes());
+    pkt[rst_tcp_idx + 16..rst_tcp_idx + 18].copy_from_slice(&tcp_checksum.to_be_bytes());
+
+    Some(pkt)
+}
+
+/// Formats bytes as rows of 16 hex octets prefixed by their offset
+pub fn hexdump(bytes: &[u8]) -> String {
+    let rows: Vec<String> = bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, row)| {
+            let octets: Vec<String> = row.iter().map(|b| format!("{b:02x}")).collect();
+            format!("{:04x}  {}", i * 16, octets.join(" "))
+        })
+        .collect();
+    rows.join("\n")
+}
 
-    pkt
+/// Pseudo header prepended to a TCP segment when computing its checksum, the
+/// IPv6 layout is the one from RFC 8200 section 8.1
+fn pseudo_header(src_ip: IpAddr, dst_ip: IpAddr, tcp_len: u16) -> Vec<u8> {
+    let mut pseudo_ip_header: Vec<u8> = Vec::with_capacity(40);
+    match (src_ip, dst_ip) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            pseudo_ip_header.extend_from_slice(&src_ip.octets());
+            pseudo_ip_header.extend_from_slice(&dst_ip.octets());
+            pseudo_ip_header.extend_from_slice(&[0x00]); //fixed 8 bit
+            pseudo_ip_header.extend_from_slice(&[0x06]); //protocol field
+            pseudo_ip_header.extend_from_slice(&tcp_len.to_be_bytes()); //TCP segment length
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            pseudo_ip_header.extend_from_slice(&src_ip.octets());
+            pseudo_ip_header.extend_from_slice(&dst_ip.octets());
+            pseudo_ip_header.extend_from_slice(&u32::from(tcp_len).to_be_bytes()); //TCP segment length
+            pseudo_ip_header.extend_from_slice(&[0x00, 0x00, 0x00]); //fixed 24 bit
+            pseudo_ip_header.extend_from_slice(&[0x06]); //next header
+        }
+        _ => unreachable!("source and destination of a packet share an IP version"),
+    }
+    pseudo_ip_header
 }
 
-// pub fn packet_checksum(packet: &Packet) -> u16 {
-//     let (src_ip, _, _, dst_ip, _, _) = src_dst_details(&packet);
-//     let mut pseudo_ip_header: Vec<u8> = Vec::with_capacity(12);
-//     pseudo_ip_header.extend_from_slice(&src_ip.octets());
-//     pseudo_ip_header.extend_from_slice(&dst_ip.octets());
-//     pseudo_ip_header.extend_from_slice(&[0x00]); //fixed 8 bit
-//     pseudo_ip_header.extend_from_slice(&[0x06]); //protocol field
-//     let tcp_seg_len = packet.data[34..].len() as u16;
-//     println!("tcp_seg_len :{tcp_seg_len}");
-//     pseudo_ip_header.extend_from_slice(&tcp_seg_len.to_be_bytes()); //TCP segment length
-//     // append rest of tcp section on to the pseudo_ip_header
-//     println!("pseude header: {} after len",pseudo_ip_header.len());
-//     for bytes in &pseudo_ip_header {
-//         print!("{:02X} ", bytes);
-//     }
-//     pseudo_ip_header.extend_from_slice(&packet[34..]);
-//     pseudo_ip_header[28] = 0;
-//     pseudo_ip_header[29] = 0;
-//     println!("pseude header: {} after body",pseudo_ip_header.len());
-//     for bytes in &pseudo_ip_header {
-//         print!("{:02X} ", bytes);
-//     }
-//     println!("My tcp checksum : {:X?}",checksum(&pseudo_ip_header));
-//     let ics = internet_checksum::checksum(&pseudo_ip_header);
-//     println!("crate tcp checksum : {:X?},{:X?}",ics[0], ics[1]);
-//     checksum(&pseudo_ip_header)
-// }
-//
 // #[cfg(target_pointer_width = "32")]
 pub fn checksum_by2(bytes: &[u8]) -> u16 {
     let mut checksum: u32 = bytes
@@ -219,7 +540,7 @@ pub fn checksum(bytes: &[u8]) -> u16 {
         .reduce(|a, b| a + b)
         .unwrap();
     let len = bytes.len();
-    if len % 4 != 0 {
+    if !len.is_multiple_of(4) {
         let slice = &bytes[len - len % 4..];
         let mut acc: u32 = 0;
         for (i, oct) in slice.iter().enumerate() {
@@ -247,7 +568,7 @@ pub fn checksum_par(bytes: &[u8]) -> u16 {
         );
     });
     let mut checksum: u64 = checksum.into_inner();
-    if len % 4 != 0 {
+    if !len.is_multiple_of(4) {
         let mut octs = [0u8; 4];
         for i in 0..(len % 4) {
             octs[i] = bytes[len - (len % 4) + i]
@@ -264,55 +585,6 @@ pub fn checksum_par(bytes: &[u8]) -> u16 {
     !checksum as u16
 }
 
-pub fn src_dst_details<'a>(
-    packet: &'a Packet,
-) -> (Ipv4Addr, u16, &'a [u8; 6], Ipv4Addr, u16, &'a [u8; 6]) {
-    let eth_header = &packet.data[0..14];
-    // println!("ethernet_header size {:?}", eth_header.len());
-    let src_mac = eth_header[0..6].try_into().unwrap();
-    let dst_mac = eth_header[6..12].try_into().unwrap();
-
-    let ip_header = &packet.data[14..34];
-    let src_ip = Ipv4Addr::new(ip_header[12], ip_header[13], ip_header[14], ip_header[15]);
-    let dst_ip = Ipv4Addr::new(ip_header[16], ip_header[17], ip_header[18], ip_header[19]);
-    // let src_ip = Ipv4Addr::from(ip_header[12..16]);
-    // let dst_ip = Ipv4Addr::from(ip_header[16..20]);
-    // let tcp_header = &packet.data[34..]; // 34..54
-    let tcp_header = &packet.data[tcp_header_idx(packet).into()..]; // 34..54
-    let src_port_bytes = [tcp_header[0], tcp_header[1]];
-    let src_port = u16::from_be_bytes(src_port_bytes);
-
-    let dst_port_bytes = [tcp_header[2], tcp_header[3]];
-    let dst_port = u16::from_be_bytes(dst_port_bytes);
-
-    (src_ip, src_port, src_mac, dst_ip, dst_port, dst_mac)
-}
-
-/// Extracts TCP details from a packet
-/// # Returns
-/// A tuple with
-/// * sequence number
-/// * ack number
-/// * window size
-/// * payload len
-pub fn tcp_details(tcp_header: &[u8]) -> (u32, u32, u16) {
-    // let tcp_header = &packet.data[34..]; //54
-    // println!("tcp_header size {:?}", tcp_header.len());
-    let seq_num = u32::from_be_bytes([tcp_header[4], tcp_header[5], tcp_header[6], tcp_header[7]]);
-    // let window_bytes = &tcp_header[12..16];
-    let window_bytes = &tcp_header[14..16];
-    let window_size = u16::from_be_bytes([window_bytes[0], window_bytes[1]]);
-    let ack_num =
-        u32::from_be_bytes([tcp_header[8], tcp_header[9], tcp_header[10], tcp_header[11]]);
-    println!(
-        "seq_num: {}, ack_num: {}, window_size: {}",
-        seq_num, ack_num, window_size
-    );
-    // let tcp_data_offset = tcp_header[12] >> 4;
-    // let payload_len = packet.len() - (34 + (tcp_data_offset * 4)) as usize;
-    (seq_num, ack_num, window_size)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -375,6 +647,254 @@ mod tests {
         assert_eq!(!checksum(bytes), 0xddf2);
     }
 
+    /// ethernet + 20 byte IPv4 + 20 byte TCP frame from 192.168.0.103:51000 to
+    /// 93.184.216.34:443
+    fn tcp_frame(flags: u8, seq: u32, ack: u32) -> Vec<u8> {
+        let mut frame = vec![
+            0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, // dst mac
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, // src mac
+            0x08, 0x00, // ethertype
+            0x45, 0x00, 0x00, 0x28, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, // ip
+            192, 168, 0, 103, // src ip
+            93, 184, 216, 34, // dst ip
+            0xc7, 0x38, 0x01, 0xbb, // ports
+        ];
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&ack.to_be_bytes());
+        frame.extend_from_slice(&[0x50, flags, 0xfa, 0xf0, 0x00, 0x00, 0x00, 0x00]);
+        frame
+    }
+
+    fn rst_for(frame: &[u8]) -> Vec<u8> {
+        build_rst_packet_from(&ParsedPacket::parse(frame).unwrap()).unwrap()
+    }
+
+    fn rst_fields(rst: &[u8]) -> (u32, u32, u8) {
+        let rst = ParsedPacket::parse(rst).unwrap();
+        let tcp = rst.tcp().unwrap();
+        (tcp.seq_num, tcp.ack_num, tcp.flags)
+    }
+
+    #[test]
+    fn rst_for_syn_acknowledges_initiator_isn() {
+        let rst = rst_for(&tcp_frame(0b0000_0010, 1_000, 0));
+        assert_eq!(rst_fields(&rst), (0, 1_001, 0b0001_0100));
+    }
+
+    #[test]
+    fn rst_for_syn_wraps_sequence_number() {
+        let rst = rst_for(&tcp_frame(0b0000_0010, u32::MAX, 0));
+        assert_eq!(rst_fields(&rst), (0, 0, 0b0001_0100));
+    }
+
+    #[test]
+    fn rst_for_ack_uses_acknowledged_sequence() {
+        let rst = rst_for(&tcp_frame(0b0001_0000, 1_000, 77_000));
+        assert_eq!(rst_fields(&rst), (77_000, 0, 0b0000_0100));
+    }
+
+    #[test]
+    fn rst_for_syn_ack_uses_acknowledged_sequence() {
+        // the server only accepts a RST inside its window, which starts at
+        // the sequence number it acknowledged
+        let rst = rst_for(&tcp_frame(0b0001_0010, 5_000, 1_001));
+        assert_eq!(rst_fields(&rst), (1_001, 0, 0b0000_0100));
+    }
+
+    #[test]
+    fn rst_is_addressed_back_to_sender() {
+        let rst = rst_for(&tcp_frame(0b0000_0010, 1_000, 0));
+        let rst = ParsedPacket::parse(&rst).unwrap();
+        assert_eq!(
+            rst.link().dst_mac.unwrap(),
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66]
+        );
+        assert_eq!(
+            rst.link().src_mac.unwrap(),
+            &[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]
+        );
+        assert_eq!(rst.ip().src, IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)));
+        assert_eq!(rst.ip().dst, IpAddr::V4(Ipv4Addr::new(192, 168, 0, 103)));
+        assert_eq!(rst.ports(), Some((443, 51000)));
+    }
+
+    #[test]
+    fn rst_checksums_are_valid() {
+        let rst = rst_for(&tcp_frame(0b0000_0010, 1_000, 0));
+        assert_eq!(checksum(&rst[14..34]), 0);
+        let mut pseudo = Vec::new();
+        pseudo.extend_from_slice(&rst[26..34]);
+        pseudo.extend_from_slice(&[0x00, 0x06, 0x00, 0x14]);
+        pseudo.extend_from_slice(&rst[34..]);
+        assert_eq!(checksum(&pseudo), 0);
+    }
+
+    #[test]
+    fn ethernet_padding_is_not_payload() {
+        let mut frame = tcp_frame(0b0001_0000, 1_000, 0);
+        frame.extend_from_slice(&[0; 6]);
+        let packet = ParsedPacket::parse(&frame).unwrap();
+        assert!(packet.tcp().unwrap().payload.is_empty());
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let frame = tcp_frame(0b0000_0010, 1_000, 0);
+        for len in 0..frame.len() {
+            assert!(ParsedPacket::parse(&frame[..len]).is_err(), "len {len}");
+        }
+        let frame = tcp6_frame(0b0000_0010, 1_000, 0);
+        for len in 0..frame.len() {
+            assert!(ParsedPacket::parse(&frame[..len]).is_err(), "len {len}");
+        }
+    }
+
+    #[test]
+    fn bogus_header_lengths_are_rejected() {
+        let mut frame = tcp_frame(0b0000_0010, 1_000, 0);
+        frame[14] = 0x4f;
+        frame[16..18].copy_from_slice(&80u16.to_be_bytes());
+        assert_eq!(
+            ParsedPacket::parse(&frame).unwrap_err(),
+            PacketError::Truncated("IPv4")
+        );
+        let mut frame = tcp_frame(0b0000_0010, 1_000, 0);
+        frame[14] = 0x44;
+        assert_eq!(
+            ParsedPacket::parse(&frame).unwrap_err(),
+            PacketError::InvalidHeaderLength("IPv4")
+        );
+        let mut frame = tcp_frame(0b0000_0010, 1_000, 0);
+        frame[46] = 0x40;
+        assert_eq!(
+            ParsedPacket::parse(&frame).unwrap_err(),
+            PacketError::InvalidHeaderLength("TCP")
+        );
+    }
+
+    #[test]
+    fn unknown_ether_types_are_rejected() {
+        let mut frame = tcp_frame(0b0000_0010, 1_000, 0);
+        frame[12..14].copy_from_slice(&[0x08, 0x06]);
+        assert_eq!(
+            ParsedPacket::parse(&frame).unwrap_err(),
+            PacketError::UnsupportedEtherType(0x0806)
+        );
+    }
+
+    #[test]
+    fn vlan_tags_are_skipped_and_kept_in_the_rst() {
+        let mut frame = tcp_frame(TCP_SYN, 1000, 0);
+        frame.splice(12..12, [0x81, 0x00, 0x00, 0x64]);
+        let packet = ParsedPacket::parse(&frame).unwrap();
+        assert_eq!(packet.link().vlan_tags, [0x81, 0x00, 0x00, 0x64]);
+        assert_eq!(packet.ports(), Some((51000, 443)));
+
+        let rst = rst_for(&frame);
+        assert_eq!(rst[12..16], [0x81, 0x00, 0x00, 0x64]);
+        assert_eq!(checksum(&rst[18..38]), 0);
+        assert_eq!(rst_fields(&rst), (0, 1001, TCP_RST | TCP_ACK));
+    }
+
+    #[test]
+    fn cooked_captures_only_have_a_source_mac() {
+        let frame = tcp_frame(TCP_SYN, 1000, 0);
+        let mut sll = vec![0x00, 0x04, 0x00, 0x01, 0x00, 0x06];
+        sll.extend_from_slice(&frame[6..12]);
+        sll.extend_from_slice(&[0x00, 0x00, 0x08, 0x00]);
+        sll.extend_from_slice(&frame[14..]);
+
+        let packet = ParsedPacket::parse_link(&sll, LinkType::LinuxSll).unwrap();
+        assert_eq!(
+            packet.link().src_mac,
+            Some(&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66])
+        );
+        assert_eq!(packet.link().dst_mac, None);
+        assert_eq!(packet.ip().src, IpAddr::from([192, 168, 0, 103]));
+        // no way to address the reply frame
+        assert!(build_rst_packet_from(&packet).is_none());
+    }
+
+    /// ethernet + IPv6 with a hop-by-hop options header + 20 byte TCP frame from
+    /// [2001:db8::103]:51000 to [2001:db8::1]:443
+    fn tcp6_frame(flags: u8, seq: u32, ack: u32) -> Vec<u8> {
+        let mut frame = vec![
+            0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, // dst mac
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, // src mac
+            0x86, 0xdd, // ethertype
+            0x60, 0x00, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x40, // ip, next header hop-by-hop
+        ];
+        frame.extend_from_slice(&"2001:db8::103".parse::<Ipv6Addr>().unwrap().octets());
+        frame.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        frame.extend_from_slice(&[0x06, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00]); // hop-by-hop
+        frame.extend_from_slice(&[0xc7, 0x38, 0x01, 0xbb]); // ports
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&ack.to_be_bytes());
+        frame.extend_from_slice(&[0x50, flags, 0xfa, 0xf0, 0x00, 0x00, 0x00, 0x00]);
+        frame
+    }
+
+    #[test]
+    fn ipv6_extension_headers_are_skipped() {
+        let frame = tcp6_frame(0b0000_0010, 1_000, 0);
+        let packet = ParsedPacket::parse(&frame).unwrap();
+        assert_eq!(packet.ip().protocol, 6);
+        assert_eq!(packet.ip().src, "2001:db8::103".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.ip().dst, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.ports(), Some((51000, 443)));
+    }
+
+    #[test]
+    fn later_fragments_have_no_transport_header() {
+        let mut frame = tcp6_frame(0b0000_0010, 1_000, 0);
+        // swap the hop-by-hop options for a fragment header
+        frame[20] = 44;
+        frame[54..62].copy_from_slice(&[0x06, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x2a]);
+        let packet = ParsedPacket::parse(&frame).unwrap();
+        assert_eq!(packet.ports(), Some((51000, 443)));
+
+        // offset 23, in 8 byte units
+        frame[56..58].copy_from_slice(&(23u16 << 3).to_be_bytes());
+        let packet = ParsedPacket::parse(&frame).unwrap();
+        assert_eq!(packet.ip().protocol, 6);
+        assert!(matches!(packet.transport(), Transport::Other(6)));
+        assert_eq!(packet.ports(), None);
+
+        let mut frame = tcp_frame(0b0000_0010, 1_000, 0);
+        frame[20..22].copy_from_slice(&23u16.to_be_bytes());
+        let packet = ParsedPacket::parse(&frame).unwrap();
+        assert!(matches!(packet.transport(), Transport::Other(6)));
+    }
+
+    #[test]
+    fn ipv6_rst_for_syn_acknowledges_initiator_isn() {
+        let rst = rst_for(&tcp6_frame(0b0000_0010, 1_000, 0));
+        assert_eq!(rst_fields(&rst), (0, 1_001, 0b0001_0100));
+        let rst = ParsedPacket::parse(&rst).unwrap();
+        assert_eq!(rst.ip().src, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(rst.ip().dst, "2001:db8::103".parse::<IpAddr>().unwrap());
+        assert_eq!(rst.ports(), Some((443, 51000)));
+    }
+
+    #[test]
+    fn ipv6_rst_checksum_is_valid() {
+        let rst = rst_for(&tcp6_frame(0b0001_0000, 1_000, 77_000));
+        let mut pseudo = Vec::new();
+        pseudo.extend_from_slice(&rst[22..54]);
+        pseudo.extend_from_slice(&[0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x06]);
+        pseudo.extend_from_slice(&rst[54..]);
+        assert_eq!(checksum(&pseudo), 0);
+    }
+
+    #[test]
+    fn hexdump_rows_hold_sixteen_octets() {
+        let bytes: Vec<u8> = (0..18).collect();
+        assert_eq!(
+            hexdump(&bytes),
+            "0000  00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n0010  10 11"
+        );
+    }
+
     // checksum for ip
     // checksum for tcp
     // checksum valid for a packet
@@ -0,0 +1,386 @@
+use chrono::{Local, Offset, TimeZone};
+use slog::info;
+use slog_scope::logger;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::Duration,
+};
+use toml::Value;
+
+use crate::{
+    errors::ConfigError,
+    filters::{Filter, PacketAction},
+    flows::Flow,
+};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Limits of a `quota` filter, the flows it matches are only monitored until
+/// one of them is exceeded, then they're reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Quota {
+    /// Payload bytes of a single flow, both directions
+    pub bytes: Option<u64>,
+    /// Age of a single flow
+    pub duration: Option<Duration>,
+    /// Payload bytes of all the flows a host opened during a period
+    pub host_bytes: Option<u64>,
+    /// Periods start at local midnight when they're a whole number of days
+    pub period: Duration,
+}
+
+impl TryFrom<&Value> for Quota {
+    type Error = ConfigError;
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        if !value.is_table() {
+            return Err(invalid(
+                "expected a table of bytes, duration, host_bytes and period",
+            ));
+        }
+        let quota = Self {
+            bytes: value.get("bytes").map(size_from_value).transpose()?,
+            duration: value.get("duration").map(duration_from_value).transpose()?,
+            host_bytes: value.get("host_bytes").map(size_from_value).transpose()?,
+            period: value
+                .get("period")
+                .map(duration_from_value)
+                .transpose()?
+                .unwrap_or(DAY),
+        };
+        if quota.bytes.is_none() && quota.duration.is_none() && quota.host_bytes.is_none() {
+            return Err(invalid("set at least one of bytes, duration or host_bytes"));
+        }
+        Ok(quota)
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> ConfigError {
+    ConfigError::InvalidQuota(reason.into())
+}
+
+/// A number of bytes, or a string like `500MB` or `1.5GiB`
+fn size_from_value(value: &Value) -> Result<u64, ConfigError> {
+    let err = || {
+        invalid(format!(
+            "invalid size [{value}], expected bytes or a string like 500MB"
+        ))
+    };
+    if let Some(n) = value.as_integer() {
+        return u64::try_from(n).map_err(|_| err());
+    }
+    let s = value.as_str().ok_or_else(err)?.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let unit: f64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1.,
+        "kb" => 1e3,
+        "mb" => 1e6,
+        "gb" => 1e9,
+        "tb" => 1e12,
+        "kib" => 1024f64,
+        "mib" => 1024f64.powi(2),
+        "gib" => 1024f64.powi(3),
+        "tib" => 1024f64.powi(4),
+        _ => return Err(err()),
+    };
+    let number: f64 = number.trim().parse().map_err(|_| err())?;
+    if !number.is_finite() || number < 0. {
+        return Err(err());
+    }
+    Ok((number * unit) as u64)
+}
+
+/// A number of seconds, or a string like `90s`, `30m`, `2h` or `1d`
+fn duration_from_value(value: &Value) -> Result<Duration, ConfigError> {
+    let err = || {
+        invalid(format!(
+            "invalid duration [{value}], expected seconds or a string like 30m"
+        ))
+    };
+    let secs = match value.as_integer() {
+        Some(n) => u64::try_from(n).map_err(|_| err())?,
+        None => {
+            let s = value.as_str().ok_or_else(err)?.trim();
+            let (number, unit) = s.split_at(s.len().saturating_sub(1));
+            let unit = match unit {
+                "s" => 1,
+                "m" => 60,
+                "h" => 60 * 60,
+                "d" => DAY.as_secs(),
+                _ => return Err(err()),
+            };
+            number.trim().parse::<u64>().map_err(|_| err())? * unit
+        }
+    };
+    if secs == 0 {
+        return Err(err());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// What a host transferred over the flows of one quota filter
+#[derive(Debug)]
+struct HostUsage {
+    /// Period the bytes were counted in
+    period: u64,
+    bytes: u64,
+    tripped: bool,
+}
+
+/// Per-host accounting for the quota filters, per-flow limits are checked
+/// against the counters flows keep anyway
+#[derive(Default)]
+pub struct Quotas {
+    /// Keyed by the rule id of the filter, its limits and the host that
+    /// opened the flows. Rule ids survive reloads and filter changes, and
+    /// changing the limits of a rule starts its count over.
+    hosts: HashMap<(usize, Quota, IpAddr), HostUsage>,
+}
+
+impl Quotas {
+    /// Counts what `flow` carried since its last check, both directions,
+    /// against the quota of filter `idx`, and tells whether to keep
+    /// monitoring the flow or to reset it
+    pub fn check(
+        &mut self,
+        idx: usize,
+        filter: &Filter,
+        flow: &mut Flow,
+        now: Duration,
+    ) -> PacketAction {
+        let Some(quota) = &filter.quota else {
+            return PacketAction::Monitor;
+        };
+        let flow_bytes = flow.bytes.iter().sum::<u64>();
+        // only the direction the filter matched gets checked, the other one
+        // is caught up on here
+        let bytes = flow_bytes - flow.quota_counted;
+        flow.quota_counted = flow_bytes;
+        let mut exceeded = false;
+        if let Some(host_bytes) = quota.host_bytes {
+            let host = flow.initiator.ip();
+            let period = period_of(now, quota.period);
+            let usage = self
+                .hosts
+                .entry((filter.rule, *quota, host))
+                .or_insert(HostUsage {
+                    period,
+                    bytes: 0,
+                    tripped: false,
+                });
+            if usage.period != period {
+                *usage = HostUsage {
+                    period,
+                    bytes: 0,
+                    tripped: false,
+                };
+            }
+            usage.bytes += bytes;
+            if usage.bytes > host_bytes && !usage.tripped {
+                usage.tripped = true;
+                info!(
+                    logger(),
+                    "Quota of filter #{} (rule {}) tripped by host {}: {} bytes this period, limit {}",
+                    idx + 1,
+                    filter.rule,
+                    host,
+                    usage.bytes,
+                    host_bytes
+                );
+            }
+            exceeded |= usage.tripped;
+        }
+
+        let age = flow.last_seen.saturating_sub(flow.first_seen);
+        let over_bytes = quota.bytes.is_some_and(|limit| flow_bytes > limit);
+        let over_time = quota.duration.is_some_and(|limit| age > limit);
+        if (over_bytes || over_time) && !flow.quota_tripped {
+            info!(
+                logger(),
+                "Quota of filter #{} (rule {}) tripped by flow {}",
+                idx + 1,
+                filter.rule,
+                flow
+            );
+        }
+        if exceeded || over_bytes || over_time {
+            flow.quota_tripped = true;
+            PacketAction::Reset
+        } else {
+            PacketAction::Monitor
+        }
+    }
+
+    /// Forgets the hosts whose period is over, and the ones of rules that
+    /// are gone or whose limits changed
+    pub fn expire(&mut self, filters: &[Filter], now: Duration) {
+        let live: HashSet<(usize, Quota)> = filters
+            .iter()
+            .filter_map(|f| f.quota.map(|q| (f.rule, q)))
+            .collect();
+        self.hosts.retain(|(rule, quota, _), usage| {
+            live.contains(&(*rule, *quota)) && usage.period == period_of(now, quota.period)
+        });
+    }
+}
+
+/// Index of the period `now` falls in, counted in local time so daily
+/// periods roll over at midnight
+fn period_of(now: Duration, period: Duration) -> u64 {
+    let offset = Local
+        .timestamp_opt(now.as_secs() as i64, 0)
+        .single()
+        .map_or(0, |t| t.offset().fix().local_minus_utc() as i64);
+    let local = now.as_secs().saturating_add_signed(offset);
+    local / period.as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{duration_from_value, size_from_value, Quota, Quotas};
+    use crate::{
+        filters::{Filter, PacketAction, TransportProto},
+        flows::Flow,
+    };
+    use std::time::Duration;
+    use toml::Value;
+
+    fn quota_filter(quota: &str) -> Filter {
+        let value = format!("quota = {quota}").parse::<Value>().unwrap();
+        Filter {
+            rule: 1,
+            quota: Some(Quota::try_from(&value["quota"]).unwrap()),
+            ..Filter::any(PacketAction::Quota)
+        }
+    }
+
+    fn flow(port: u16, secs: u64) -> Flow {
+        Flow::new(
+            TransportProto::Tcp,
+            format!("192.168.0.103:{port}").parse().unwrap(),
+            "93.184.216.34:443".parse().unwrap(),
+            Duration::from_secs(secs),
+        )
+    }
+
+    #[test]
+    fn sizes_and_durations() {
+        let size = |s: &str| size_from_value(&Value::String(s.to_string())).unwrap();
+        assert_eq!(size("500MB"), 500_000_000);
+        assert_eq!(size("1.5 KiB"), 1536);
+        assert_eq!(size_from_value(&Value::Integer(42)).unwrap(), 42);
+        assert!(size_from_value(&Value::String("5 parsecs".to_string())).is_err());
+        let duration = |s: &str| duration_from_value(&Value::String(s.to_string())).unwrap();
+        assert_eq!(duration("30m"), Duration::from_secs(1800));
+        assert_eq!(duration("1d"), Duration::from_secs(86400));
+        assert!(duration_from_value(&Value::String("0s".to_string())).is_err());
+    }
+
+    #[test]
+    fn flows_are_reset_past_their_bytes_or_age() {
+        let filter = quota_filter(r#"{ bytes = 1000, duration = "1m" }"#);
+        let mut quotas = Quotas::default();
+        let mut f = flow(51000, 0);
+        f.bytes = [600, 400];
+        assert!(matches!(
+            quotas.check(0, &filter, &mut f, Duration::ZERO),
+            PacketAction::Monitor
+        ));
+        f.bytes[1] += 1;
+        assert!(matches!(
+            quotas.check(0, &filter, &mut f, Duration::ZERO),
+            PacketAction::Reset
+        ));
+        assert!(f.quota_tripped);
+
+        let mut old = flow(51001, 0);
+        old.last_seen = Duration::from_secs(61);
+        assert!(matches!(
+            quotas.check(0, &filter, &mut old, Duration::from_secs(61)),
+            PacketAction::Reset
+        ));
+    }
+
+    #[test]
+    fn host_quota_spans_flows_and_resets_with_the_period() {
+        let filter = quota_filter(r#"{ host_bytes = "1KB", period = "1h" }"#);
+        let mut quotas = Quotas::default();
+        let hour = Duration::from_secs(3600);
+        let now = 1000 * hour;
+        let (mut a, mut b) = (flow(51000, 0), flow(51001, 0));
+        a.bytes[0] += 800;
+        assert!(matches!(
+            quotas.check(0, &filter, &mut a, now),
+            PacketAction::Monitor
+        ));
+        // another flow of the same host, still within the hour
+        b.bytes[0] += 300;
+        assert!(matches!(
+            quotas.check(0, &filter, &mut b, now + Duration::from_secs(60)),
+            PacketAction::Reset
+        ));
+        assert!(matches!(
+            quotas.check(0, &filter, &mut a, now + Duration::from_secs(120)),
+            PacketAction::Reset
+        ));
+        quotas.expire(std::slice::from_ref(&filter), now + hour);
+        assert!(quotas.hosts.is_empty());
+        a.bytes[0] += 10;
+        assert!(matches!(
+            quotas.check(0, &filter, &mut a, now + hour),
+            PacketAction::Monitor
+        ));
+    }
+
+    #[test]
+    fn host_quota_counts_what_the_server_sent() {
+        let filter = quota_filter(r#"{ host_bytes = "1KB" }"#);
+        let mut quotas = Quotas::default();
+        let now = Duration::from_secs(1000 * 3600);
+        let mut a = flow(51000, 0);
+        a.bytes = [200, 0];
+        assert!(matches!(
+            quotas.check(0, &filter, &mut a, now),
+            PacketAction::Monitor
+        ));
+        // the response matched no filter on its way back, the next
+        // request still brings it into the count
+        a.bytes = [250, 5000];
+        assert!(matches!(
+            quotas.check(0, &filter, &mut a, now),
+            PacketAction::Reset
+        ));
+        assert_eq!(quotas.hosts.values().next().unwrap().bytes, 5250);
+    }
+
+    #[test]
+    fn host_usage_survives_a_reload() {
+        let filter = quota_filter(r#"{ host_bytes = "1KB" }"#);
+        let mut quotas = Quotas::default();
+        let now = Duration::from_secs(1000 * 3600);
+        let mut a = flow(51000, 0);
+        a.bytes[0] += 800;
+        assert!(matches!(
+            quotas.check(0, &filter, &mut a, now),
+            PacketAction::Monitor
+        ));
+
+        // the same config loaded again, with a filter added in front
+        let mut added = quota_filter(r#"{ bytes = "1MB" }"#);
+        added.rule = 2;
+        let reloaded = [added, quota_filter(r#"{ host_bytes = "1KB" }"#)];
+        quotas.expire(&reloaded, now);
+        let mut b = flow(51001, 0);
+        b.bytes[0] += 300;
+        assert!(matches!(
+            quotas.check(1, &reloaded[1], &mut b, now),
+            PacketAction::Reset
+        ));
+
+        // new limits start the count over
+        let changed = [quota_filter(r#"{ host_bytes = "2KB" }"#)];
+        quotas.expire(&changed, now);
+        assert!(quotas.hosts.is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use crate::packet_utils::TcpSegment;
+
+/// Enough for the largest DNS message and its length prefix
+const MAX_BUFFERED: usize = 64 * 1024 + 2;
+/// Segments held back waiting for a gap to fill
+const MAX_OUT_OF_ORDER: usize = 16;
+const MAX_STREAMS: usize = 1024;
+const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Puts the payloads of one direction of TCP connections back in order.
+/// Only meant for the few short streams whose content is parsed, like DNS
+/// over TCP or the start of a TLS handshake, so the buffering is bounded and
+/// anything odd drops the stream.
+#[derive(Default)]
+pub struct Streams {
+    streams: HashMap<(SocketAddr, SocketAddr), Stream>,
+}
+
+struct Stream {
+    next_seq: u32,
+    /// Bytes received in order and not consumed yet
+    buf: Vec<u8>,
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    last_seen: Duration,
+}
+
+impl Stream {
+    fn new(next_seq: u32, ts: Duration) -> Self {
+        Self {
+            next_seq,
+            buf: Vec::new(),
+            out_of_order: Vec::new(),
+            last_seen: ts,
+        }
+    }
+
+    /// Appends the part of a segment that wasn't received yet, returns false
+    /// if it starts after a gap
+    fn append(&mut self, seq: u32, payload: &[u8]) -> bool {
+        // sequence numbers wrap, compare them by distance
+        let offset = seq.wrapping_sub(self.next_seq) as i32;
+        if offset > 0 {
+            return false;
+        }
+        let skip = offset.unsigned_abs() as usize;
+        if let Some(new) = payload.get(skip..) {
+            self.buf.extend_from_slice(new);
+            self.next_seq = self.next_seq.wrapping_add(new.len() as u32);
+        }
+        true
+    }
+}
+
+impl Streams {
+    /// Adds a segment sent from `src` to `dst` and returns the bytes of the
+    /// stream received in order so far. The caller drains what it consumed.
+    /// `None` if the stream was closed or dropped.
+    pub fn push(
+        &mut self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        tcp: &TcpSegment,
+        ts: Duration,
+    ) -> Option<&mut Vec<u8>> {
+        let key = (src, dst);
+        if tcp.is_rst() {
+            self.streams.remove(&key);
+            return None;
+        }
+        if tcp.is_syn() {
+            // the SYN takes one sequence number
+            self.streams
+                .insert(key, Stream::new(tcp.seq_num.wrapping_add(1), ts));
+        }
+        if tcp.payload.is_empty() {
+            return None;
+        }
+        if !self.streams.contains_key(&key) && self.streams.len() >= MAX_STREAMS {
+            return None;
+        }
+        // a stream picked up without its SYN starts at the first data seen
+        let stream = self
+            .streams
+            .entry(key)
+            .or_insert_with(|| Stream::new(tcp.seq_num, ts));
+        stream.last_seen = ts;
+
+        if !stream.append(tcp.seq_num, tcp.payload) {
+            if stream.out_of_order.len() >= MAX_OUT_OF_ORDER {
+                self.streams.remove(&key);
+                return None;
+            }
+            stream
+                .out_of_order
+                .push((tcp.seq_num, tcp.payload.to_vec()));
+        }
+        while let Some(idx) = stream
+            .out_of_order
+            .iter()
+            .position(|(seq, _)| seq.wrapping_sub(stream.next_seq) as i32 <= 0)
+        {
+            let (seq, payload) = stream.out_of_order.swap_remove(idx);
+            stream.append(seq, &payload);
+        }
+        if stream.buf.len() > MAX_BUFFERED {
+            self.streams.remove(&key);
+            return None;
+        }
+        self.streams.get_mut(&key).map(|s| &mut s.buf)
+    }
+
+    /// Drops a stream whose content isn't needed anymore
+    pub fn remove(&mut self, src: SocketAddr, dst: SocketAddr) {
+        self.streams.remove(&(src, dst));
+    }
+
+    /// Drops streams idle for too long
+    pub fn expire(&mut self, now: Duration) {
+        self.streams
+            .retain(|_, s| now.saturating_sub(s.last_seen) <= STREAM_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Streams;
+    use crate::packet_utils::TcpSegment;
+    use std::{net::SocketAddr, time::Duration};
+
+    fn segment(seq: u32, flags: u8, payload: &[u8]) -> TcpSegment<'_> {
+        TcpSegment {
+            src_port: 53,
+            dst_port: 40000,
+            seq_num: seq,
+            ack_num: 0,
+            flags,
+            window_size: 0,
+            payload,
+        }
+    }
+
+    fn push(streams: &mut Streams, seq: u32, flags: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        let src: SocketAddr = "10.0.0.53:53".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        streams
+            .push(src, dst, &segment(seq, flags, payload), Duration::ZERO)
+            .map(|b| b.clone())
+    }
+
+    #[test]
+    fn segments_are_put_back_in_order() {
+        let mut streams = Streams::default();
+        push(&mut streams, u32::MAX, 0x12, b"");
+        // the SYN wrapped the sequence number to 0
+        assert_eq!(push(&mut streams, 3, 0x10, b"def").unwrap(), b"");
+        assert_eq!(push(&mut streams, 0, 0x10, b"abc").unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn retransmitted_bytes_are_skipped() {
+        let mut streams = Streams::default();
+        push(&mut streams, 100, 0x10, b"abc");
+        assert_eq!(push(&mut streams, 101, 0x10, b"bcde").unwrap(), b"abcde");
+        assert_eq!(push(&mut streams, 100, 0x10, b"ab").unwrap(), b"abcde");
+    }
+
+    #[test]
+    fn reset_drops_the_stream() {
+        let mut streams = Streams::default();
+        push(&mut streams, 100, 0x10, b"abc");
+        assert!(push(&mut streams, 103, 0x04, b"").is_none());
+        assert_eq!(push(&mut streams, 500, 0x10, b"x").unwrap(), b"x");
+    }
+}
@@ -0,0 +1,148 @@
+use slog::{debug, warn};
+use slog_scope::logger;
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::errors::DomainError;
+
+/// Pending lookups beyond this are dropped and asked again on a later packet
+const QUEUE_LEN: usize = 1024;
+
+pub type Resolution = (IpAddr, Result<String, DomainError>);
+
+/// Reverse lookups run on worker threads so a slow or unresolvable address
+/// never stalls the capture loop. Requests for an address already being
+/// resolved are merged.
+pub struct ResolverPool {
+    requests: SyncSender<IpAddr>,
+    results: Receiver<Resolution>,
+    in_flight: HashSet<IpAddr>,
+}
+
+impl ResolverPool {
+    pub fn spawn(threads: usize, resolve: fn(&IpAddr) -> Result<String, DomainError>) -> Self {
+        let (requests, queue) = mpsc::sync_channel::<IpAddr>(QUEUE_LEN);
+        let (done, results) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads.max(1) {
+            let queue = Arc::clone(&queue);
+            let done = done.clone();
+            thread::Builder::new()
+                .name(format!("resolver-{i}"))
+                .spawn(move || loop {
+                    let next = queue.lock().map(|q| q.recv());
+                    let Ok(Ok(ip)) = next else {
+                        // the pool was dropped
+                        return;
+                    };
+                    if done.send((ip, resolve(&ip))).is_err() {
+                        return;
+                    }
+                })
+                .expect("failed to spawn resolver thread");
+        }
+        Self {
+            requests,
+            results,
+            in_flight: HashSet::new(),
+        }
+    }
+
+    /// Queues a lookup unless one for the same address is already running
+    pub fn request(&mut self, ip: IpAddr) {
+        if self.in_flight.contains(&ip) {
+            return;
+        }
+        match self.requests.try_send(ip) {
+            Ok(()) => {
+                self.in_flight.insert(ip);
+            }
+            Err(TrySendError::Full(_)) => {
+                debug!(logger(), "Resolver queue full, dropping lookup of [{}]", ip)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                warn!(
+                    logger(),
+                    "Resolver threads are gone, can't look up [{}]", ip
+                )
+            }
+        }
+    }
+
+    /// Lookups finished since the last call, never blocks
+    pub fn finished(&mut self) -> Vec<Resolution> {
+        let mut done = Vec::new();
+        loop {
+            match self.results.try_recv() {
+                Ok(res) => {
+                    self.in_flight.remove(&res.0);
+                    done.push(res);
+                }
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => return done,
+            }
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResolverPool;
+    use crate::errors::DomainError;
+    use std::{
+        net::IpAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn slow_resolve(ip: &IpAddr) -> Result<String, DomainError> {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        match ip {
+            IpAddr::V4(_) => Ok("host.example".to_string()),
+            IpAddr::V6(_) => Err(DomainError::FailedToResolve(*ip)),
+        }
+    }
+
+    fn wait_for(pool: &mut ResolverPool, count: usize) -> Vec<super::Resolution> {
+        let start = Instant::now();
+        let mut done = Vec::new();
+        while done.len() < count && start.elapsed() < Duration::from_secs(5) {
+            done.extend(pool.finished());
+            thread::sleep(Duration::from_millis(5));
+        }
+        done
+    }
+
+    #[test]
+    fn duplicate_requests_are_merged() {
+        let mut pool = ResolverPool::spawn(2, slow_resolve);
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        pool.request(v4);
+        pool.request(v4);
+        pool.request(v6);
+        assert_eq!(pool.in_flight(), 2);
+
+        let mut done = wait_for(&mut pool, 2);
+        done.sort_by_key(|(ip, _)| *ip);
+        assert_eq!(done.len(), 2);
+        assert_eq!(done[0].1.as_deref().unwrap(), "host.example");
+        assert!(done[1].1.is_err());
+        assert_eq!(pool.in_flight(), 0);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    }
+}
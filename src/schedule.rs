@@ -0,0 +1,276 @@
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, Timelike, Utc};
+use chrono_tz::Tz;
+use std::time::Duration;
+use toml::Value;
+
+use crate::errors::ConfigError;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// When a filter applies, e.g. on weekdays from 09:00 to 17:00. Every part
+/// left out of the config doesn't restrict anything.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    /// Indexed by days since Monday
+    days: [bool; 7],
+    /// Minutes since midnight, a window ending before it starts runs past
+    /// midnight and belongs to the day it started on
+    times: Vec<(u32, u32)>,
+    /// `None` for the local time of the host
+    timezone: Option<Tz>,
+    /// Inclusive date ranges
+    dates: Vec<(NaiveDate, NaiveDate)>,
+}
+
+impl Schedule {
+    /// Whether the schedule covers `now`, a duration since the epoch
+    pub fn is_active(&self, now: Duration) -> bool {
+        let utc = DateTime::<Utc>::from_timestamp(now.as_secs() as i64, 0).unwrap_or_default();
+        let local: NaiveDateTime = match self.timezone {
+            Some(tz) => utc.with_timezone(&tz).naive_local(),
+            None => utc.with_timezone(&Local).naive_local(),
+        };
+        // a window running past midnight belongs to the day it started on,
+        // both for the days of the week and the dates
+        let applies_on = |date: NaiveDate| {
+            self.days[date.weekday().num_days_from_monday() as usize]
+                && (self.dates.is_empty()
+                    || self.dates.iter().any(|(s, e)| (*s..=*e).contains(&date)))
+        };
+        let today = local.date();
+        if self.times.is_empty() {
+            return applies_on(today);
+        }
+        let yesterday = today.pred_opt().unwrap_or(today);
+        let minute = local.hour() * 60 + local.minute();
+        self.times.iter().any(|&(start, end)| {
+            if start < end {
+                applies_on(today) && (start..end).contains(&minute)
+            } else {
+                (applies_on(today) && minute >= start) || (applies_on(yesterday) && minute < end)
+            }
+        })
+    }
+}
+
+impl TryFrom<&Value> for Schedule {
+    type Error = ConfigError;
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        if !value.is_table() {
+            return Err(invalid(
+                "expected a table of days, times, timezone and dates",
+            ));
+        }
+        let strings = |key: &str| -> Result<Vec<&str>, ConfigError> {
+            let Some(v) = value.get(key) else {
+                return Ok(Vec::new());
+            };
+            v.as_array()
+                .ok_or(ConfigError::ExpectedAList)?
+                .iter()
+                .map(|i| {
+                    i.as_str()
+                        .ok_or(ConfigError::FailedToParseAsString(i.clone()))
+                })
+                .collect()
+        };
+
+        let days = strings("days")?;
+        let mut day_set = [days.is_empty(); 7];
+        for d in days {
+            let (first, last) = match d.split_once('-') {
+                Some((first, last)) => (day_from_str(first)?, day_from_str(last)?),
+                None => (day_from_str(d)?, day_from_str(d)?),
+            };
+            // ranges may wrap around the week, like fri-mon
+            let len = (last + 7 - first) % 7;
+            for i in 0..=len {
+                day_set[(first + i) % 7] = true;
+            }
+        }
+        let times = strings("times")?
+            .into_iter()
+            .map(time_range_from_str)
+            .collect::<Result<_, _>>()?;
+        let timezone = match value.get("timezone") {
+            Some(v) => {
+                let name = v
+                    .as_str()
+                    .ok_or(ConfigError::FailedToParseAsString(v.clone()))?;
+                Some(
+                    name.parse::<Tz>()
+                        .map_err(|_| invalid(format!("unknown timezone [{name}]")))?,
+                )
+            }
+            None => None,
+        };
+        let dates = strings("dates")?
+            .into_iter()
+            .map(date_range_from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            days: day_set,
+            times,
+            timezone,
+            dates,
+        })
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> ConfigError {
+    ConfigError::InvalidSchedule(reason.into())
+}
+
+/// Days since Monday, from a name like `mon` or `monday`
+fn day_from_str(day: &str) -> Result<usize, ConfigError> {
+    let day = day.trim().to_lowercase();
+    DAYS.iter()
+        .position(|d| day.len() >= 3 && (*d == day || full_day_name(d) == day))
+        .ok_or_else(|| invalid(format!("unknown day [{day}]")))
+}
+
+fn full_day_name(short: &str) -> &'static str {
+    match short {
+        "mon" => "monday",
+        "tue" => "tuesday",
+        "wed" => "wednesday",
+        "thu" => "thursday",
+        "fri" => "friday",
+        "sat" => "saturday",
+        _ => "sunday",
+    }
+}
+
+/// `HH:MM-HH:MM` as minutes since midnight, `24:00` ends a window at midnight
+fn time_range_from_str(range: &str) -> Result<(u32, u32), ConfigError> {
+    let err = || {
+        invalid(format!(
+            "invalid time range [{range}], expected HH:MM-HH:MM"
+        ))
+    };
+    let minutes = |t: &str| -> Option<u32> {
+        let (h, m) = t.trim().split_once(':')?;
+        let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+        let total = h * 60 + m;
+        (m < 60 && total <= MINUTES_PER_DAY).then_some(total)
+    };
+    let (start, end) = range.split_once('-').ok_or_else(err)?;
+    let (start, end) = (
+        minutes(start).ok_or_else(err)?,
+        minutes(end).ok_or_else(err)?,
+    );
+    if start == end || start == MINUTES_PER_DAY {
+        return Err(err());
+    }
+    Ok((start, end % MINUTES_PER_DAY))
+}
+
+/// `YYYY-MM-DD` or `YYYY-MM-DD..YYYY-MM-DD`
+fn date_range_from_str(range: &str) -> Result<(NaiveDate, NaiveDate), ConfigError> {
+    let date = |d: &str| {
+        NaiveDate::parse_from_str(d.trim(), "%Y-%m-%d")
+            .map_err(|_| invalid(format!("invalid date [{d}], expected YYYY-MM-DD")))
+    };
+    let (start, end) = match range.split_once("..") {
+        Some((start, end)) => (date(start)?, date(end)?),
+        None => (date(range)?, date(range)?),
+    };
+    if start > end {
+        return Err(invalid(format!(
+            "date range [{range}] ends before it starts"
+        )));
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use std::time::Duration;
+    use toml::Value;
+
+    fn schedule(toml: &str) -> Schedule {
+        let value = format!("schedule = {toml}").parse::<Value>().unwrap();
+        Schedule::try_from(&value["schedule"]).unwrap()
+    }
+
+    /// 2026-10-19 is a Monday
+    fn at(day: u32, hour: u32, minute: u32) -> Duration {
+        let t = Utc
+            .with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+            .unwrap();
+        Duration::from_secs(t.timestamp() as u64)
+    }
+
+    #[test]
+    fn office_hours_on_weekdays() {
+        let s = schedule(r#"{ days = ["mon-fri"], times = ["09:00-17:00"], timezone = "UTC" }"#);
+        assert!(!s.is_active(at(19, 8, 59)));
+        assert!(s.is_active(at(19, 9, 0)));
+        assert!(s.is_active(at(23, 16, 59)));
+        assert!(!s.is_active(at(23, 17, 0)));
+        // saturday
+        assert!(!s.is_active(at(24, 12, 0)));
+    }
+
+    #[test]
+    fn overnight_windows_belong_to_their_first_day() {
+        let s = schedule(r#"{ days = ["fri"], times = ["22:00-06:00"], timezone = "UTC" }"#);
+        assert!(s.is_active(at(23, 23, 0)));
+        assert!(s.is_active(at(24, 5, 59)));
+        assert!(!s.is_active(at(24, 6, 0)));
+        assert!(!s.is_active(at(24, 23, 0)));
+        // thursday night isn't friday's
+        assert!(!s.is_active(at(23, 2, 0)));
+    }
+
+    #[test]
+    fn timezone_shifts_the_window() {
+        let s = schedule(r#"{ times = ["09:00-17:00"], timezone = "Asia/Tokyo" }"#);
+        // 09:00 in Tokyo
+        assert!(s.is_active(at(19, 0, 0)));
+        assert!(!s.is_active(at(19, 8, 0)));
+    }
+
+    #[test]
+    fn dates_restrict_the_days() {
+        let s = schedule(
+            r#"{ dates = ["2026-10-20", "2026-10-24..2026-10-25"], days = ["sat-tue"], timezone = "UTC" }"#,
+        );
+        assert!(!s.is_active(at(19, 12, 0)));
+        assert!(s.is_active(at(20, 12, 0)));
+        assert!(s.is_active(at(25, 12, 0)));
+        assert_eq!(
+            s.dates[1],
+            (
+                NaiveDate::from_ymd_opt(2026, 10, 24).unwrap(),
+                NaiveDate::from_ymd_opt(2026, 10, 25).unwrap()
+            )
+        );
+
+        // overnight windows follow the date they started on
+        let overnight =
+            schedule(r#"{ dates = ["2026-10-20"], times = ["22:00-06:00"], timezone = "UTC" }"#);
+        assert!(!overnight.is_active(at(20, 2, 0)));
+        assert!(overnight.is_active(at(20, 23, 0)));
+        assert!(overnight.is_active(at(21, 5, 59)));
+        assert!(!overnight.is_active(at(21, 6, 0)));
+        assert!(!overnight.is_active(at(21, 23, 0)));
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        for toml in [
+            r#"{ days = ["mun"] }"#,
+            r#"{ times = ["9-17"] }"#,
+            r#"{ times = ["09:00-09:00"] }"#,
+            r#"{ timezone = "Mars/Olympus" }"#,
+            r#"{ dates = ["2026-10-25..2026-10-20"] }"#,
+        ] {
+            let value = format!("schedule = {toml}").parse::<Value>().unwrap();
+            assert!(Schedule::try_from(&value["schedule"]).is_err(), "{toml}");
+        }
+    }
+}
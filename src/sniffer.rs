@@ -0,0 +1,512 @@
+use slog::{debug, info, warn};
+use slog_scope::logger;
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    configs::Config,
+    domains::{CacheStats, Lookup, Resolved},
+    errors::PacketError,
+    filters::{Blacklist, Match, PacketAction, TransportProto},
+    flows::{Direction, Flow, FlowTable},
+    http::{parse_request, RequestHead},
+    packet_utils::{build_rst_packet_from, LinkType, ParsedPacket, Transport},
+    quota::Quotas,
+    reassembly::Streams,
+    tls::{parse_client_hello, ClientHello},
+};
+
+/// BPF filter applied to every capture, live or offline
+pub fn capture_filter() -> String {
+    let filter_tcp_syn = "tcp[13] & 2!=0";
+    let filter_tcp_ack = "tcp[13] & 16 != 0";
+    let filter_dns_rsp = "udp src port 53 and udp[2] & 0x80 != 0";
+    // data segments carry an ACK, so the ClientHello or HTTP request opening
+    // a connection is captured along with the handshake.
+    // tcp[] and udp[] offsets only work on IPv4, the flags of IPv6 packets are
+    // checked after capture
+    let filter_ipv6 = "ip6 and (tcp or udp src port 53)";
+    let filter =
+        format!("{filter_tcp_syn} or {filter_tcp_ack} or ({filter_dns_rsp}) or ({filter_ipv6})");
+    // `vlan` shifts the offsets of everything after it, so tagged frames get
+    // the same filter again at the end
+    format!("{filter} or (vlan and ({filter}))")
+}
+
+/// How often, in capture time, idle flows are dropped and stats are logged
+const FLOW_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+/// A client sending this much without completing a ClientHello or request
+/// headers isn't speaking TLS or HTTP
+const MAX_INSPECTED: usize = 16 * 1024;
+
+/// Applies the filters to captured frames, independent of where the frames
+/// come from and of whether the resets are actually sent
+pub struct Sniffer {
+    link_type: LinkType,
+    blacklist: Blacklist,
+    domains: Resolved,
+    dns_streams: Streams,
+    /// Starts of TCP connections, until their ClientHello or HTTP request
+    /// headers are complete
+    openings: Streams,
+    state_file: Option<PathBuf>,
+    flows: FlowTable,
+    quotas: Quotas,
+    next_sweep: Duration,
+    /// Timestamp of the last packet, the clock the name cache expires on
+    now: Duration,
+    dry_run: bool,
+    packets: u64,
+    malformed: u64,
+}
+
+impl Sniffer {
+    pub fn build(config: &Config, link_type: LinkType) -> Self {
+        let mut domains = Resolved::build(config.dns.cache_size, config.dns.resolver_threads);
+        for path in &config.dns.hosts_files {
+            match domains.load_hosts(path) {
+                Ok(n) => info!(logger(), "Loaded {} names from [{}]", n, path.display()),
+                Err(e) => warn!(logger(), "{}", e),
+            }
+        }
+        if let Some(path) = &config.dns.state_file {
+            match domains.load_state(path, wall_clock()) {
+                Ok(n) => info!(logger(), "Restored {} names from [{}]", n, path.display()),
+                Err(e) => warn!(logger(), "{}", e),
+            }
+        }
+        Self {
+            link_type,
+            blacklist: Blacklist::build(&config.filter, config.on_unresolved),
+            domains,
+            dns_streams: Streams::default(),
+            openings: Streams::default(),
+            state_file: config.dns.state_file.clone(),
+            flows: FlowTable::new(config.max_flows),
+            quotas: Quotas::default(),
+            next_sweep: Duration::ZERO,
+            now: Duration::ZERO,
+            dry_run: config.dry_run,
+            packets: 0,
+            malformed: 0,
+        }
+    }
+
+    /// Swaps in the filters of a reloaded config between two packets, the
+    /// learned names and the flows are kept
+    pub fn reload(&mut self, config: &Config) {
+        self.change_filters(|blacklist| {
+            *blacklist = Blacklist::build(&config.filter, config.on_unresolved)
+        });
+        self.dry_run = config.dry_run;
+    }
+
+    pub fn blacklist(&self) -> &Blacklist {
+        &self.blacklist
+    }
+
+    /// Changes the live filters, the flows match them again from their next
+    /// packet on
+    pub fn change_filters<T>(&mut self, change: impl FnOnce(&mut Blacklist) -> T) -> T {
+        let out = change(&mut self.blacklist);
+        // cached matches refer to filters by index, quota usage follows rule ids
+        self.flows.clear_matches();
+        self.quotas.expire(self.blacklist.filters(), self.now);
+        out
+    }
+
+    pub fn flows(&self) -> &FlowTable {
+        &self.flows
+    }
+
+    /// Name of an address, a miss queues a reverse lookup
+    pub fn resolve(&mut self, ip: &IpAddr) -> Lookup {
+        self.domains.name(ip, self.now)
+    }
+
+    /// Saves the learned names to the state file, if one is configured
+    pub fn save_state(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        match self.domains.save_state(path, self.now, wall_clock()) {
+            Ok(n) => debug!(logger(), "Saved {} names to [{}]", n, path.display()),
+            Err(e) => warn!(logger(), "{}", e),
+        }
+    }
+
+    /// Number of frames handled, malformed ones included
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    /// Number of frames skipped because they couldn't be parsed
+    pub fn malformed(&self) -> u64 {
+        self.malformed
+    }
+
+    pub fn dns_stats(&self) -> CacheStats {
+        self.domains.stats()
+    }
+
+    /// Handles one frame captured at `ts`, frames that can't be parsed are
+    /// counted and returned as errors
+    pub fn handle(&mut self, data: &[u8], ts: Duration) -> Result<Decision, PacketError> {
+        self.packets += 1;
+        self.now = ts;
+        self.domains.restore(ts);
+        let packet = match ParsedPacket::parse_link(data, self.link_type) {
+            Ok(packet) => packet,
+            Err(e) => {
+                self.malformed += 1;
+                debug!(
+                    logger(),
+                    "Skipping malformed packet [{}], {} so far", e, self.malformed
+                );
+                return Err(e);
+            }
+        };
+
+        self.domains.collect_resolved(ts);
+        if let Some(udp) = packet.udp() {
+            if udp.src_port == 53 {
+                self.domains.update_from_dns(udp.payload, ts);
+            }
+        }
+        if let Some(tcp) = packet.tcp() {
+            // large answers come over TCP, split across segments
+            if tcp.src_port == 53 {
+                let src = SocketAddr::new(packet.ip().src, tcp.src_port);
+                let dst = SocketAddr::new(packet.ip().dst, tcp.dst_port);
+                if let Some(stream) = self.dns_streams.push(src, dst, tcp, ts) {
+                    self.domains.update_from_dns_stream(stream, ts);
+                }
+            }
+        }
+
+        if ts >= self.next_sweep {
+            self.sweep(ts);
+        }
+        if self.blacklist.update_schedules(ts) {
+            // a filter turning on or off changes what flows match
+            self.flows.clear_matches();
+        }
+        // filters are matched once per direction of a flow, only the prob
+        // roll is repeated for every packet. A match waiting on a reverse
+        // lookup isn't cached, the next packet tries again.
+        let (verdict, first, flow) = match self.flows.track(&packet, ts) {
+            Some((flow, dir)) => {
+                if dir == Direction::Forward
+                    && inspect_opening(&mut self.openings, flow, &packet, ts)
+                {
+                    debug!(logger(), "Learned server name: {}", flow);
+                    // filters may match differently now that the name is known
+                    flow.clear_matched();
+                }
+                let (mut verdict, mut first) = match flow.matched(dir) {
+                    Some(matched) => (self.blacklist.verdict_for(matched), false),
+                    None => {
+                        let matched =
+                            self.blacklist
+                                .match_packet(&packet, &mut self.domains, ts, Some(flow));
+                        if !matches!(matched, Match::Pending(_)) {
+                            flow.set_matched(dir, matched);
+                        }
+                        (self.blacklist.verdict_for(matched), true)
+                    }
+                };
+                if let (PacketAction::Quota, Some(idx)) = (verdict.action, verdict.filter) {
+                    let filter = &self.blacklist.filters()[idx];
+                    let tripped = flow.quota_tripped;
+                    verdict.action = self.quotas.check(idx, filter, flow, ts);
+                    // going over the quota starts the flow over as a reset one
+                    first |= !tripped && flow.quota_tripped;
+                }
+                (verdict, first, Some(&*flow))
+            }
+            None => {
+                let mut verdict = self
+                    .blacklist
+                    .get_packet_action(&packet, &mut self.domains, ts);
+                // without a flow there's nothing to count
+                if let PacketAction::Quota = verdict.action {
+                    verdict.action = PacketAction::Monitor;
+                }
+                (verdict, true, None)
+            }
+        };
+        if let (PacketAction::Monitor, true) = (verdict.action, first) {
+            warn!(
+                logger(),
+                "detected connection src:[{}] -> dst:[{}]",
+                packet.ip().src,
+                packet.ip().dst
+            );
+        }
+        let rst = reset_for(&packet, verdict.action);
+        if rst.is_some() {
+            debug!(
+                logger(),
+                "built rst src:[{}] -> dst:[{}]",
+                packet.ip().dst,
+                packet.ip().src
+            );
+        }
+        let dry_run = verdict
+            .filter
+            .and_then(|idx| self.blacklist.filters()[idx].dry_run)
+            .unwrap_or(self.dry_run);
+        let mut decision = Decision {
+            src: packet.ip().src,
+            dst: packet.ip().dst,
+            ports: packet.ports(),
+            proto: match packet.transport() {
+                Transport::Tcp(_) => Some(TransportProto::Tcp),
+                Transport::Udp(_) => Some(TransportProto::Udp),
+                Transport::Other(_) => None,
+            },
+            src_mac: packet.link().src_mac.copied(),
+            dst_mac: packet.link().dst_mac.copied(),
+            domain: None,
+            filter: verdict.filter,
+            rule: verdict.filter.map(|idx| self.blacklist.filters()[idx].rule),
+            action: verdict.action,
+            unresolved: verdict.unresolved,
+            first,
+            rst,
+            dry_run,
+        };
+        if decision.is_event() {
+            decision.domain = server_domain(&mut self.domains, &packet, flow, ts);
+        }
+        Ok(decision)
+    }
+
+    fn sweep(&mut self, now: Duration) {
+        self.dns_streams.expire(now);
+        self.openings.expire(now);
+        self.quotas.expire(self.blacklist.filters(), now);
+        for flow in self.flows.expire(now) {
+            debug!(logger(), "Flow expired: {}", flow);
+        }
+        debug!(logger(), "{} flows tracked", self.flows.len());
+        debug!(logger(), "DNS cache: {}", self.domains.stats());
+        self.next_sweep = now + FLOW_SWEEP_INTERVAL;
+    }
+}
+
+/// Feeds a segment sent by the initiator of a TCP flow to the parsers of
+/// TLS ClientHellos and HTTP requests, returns whether the flow just learned
+/// its server name or request
+fn inspect_opening(
+    openings: &mut Streams,
+    flow: &mut Flow,
+    packet: &ParsedPacket,
+    ts: Duration,
+) -> bool {
+    let Some(tcp) = packet.tcp() else {
+        return false;
+    };
+    if !flow.inspecting {
+        return false;
+    }
+    let src = SocketAddr::new(packet.ip().src, tcp.src_port);
+    let dst = SocketAddr::new(packet.ip().dst, tcp.dst_port);
+    let Some(stream) = openings.push(src, dst, tcp, ts) else {
+        return false;
+    };
+    let more = stream.len() < MAX_INSPECTED;
+    match parse_client_hello(stream) {
+        ClientHello::Sni(sni) => flow.sni = Some(sni),
+        ClientHello::Incomplete if more => return false,
+        ClientHello::NotTls => match parse_request(stream) {
+            RequestHead::Complete(req) => flow.http = Some(req),
+            RequestHead::Incomplete if more => return false,
+            RequestHead::Incomplete | RequestHead::NotHttp => {}
+        },
+        ClientHello::Incomplete | ClientHello::NoSni => {}
+    }
+    // one look at the start of the connection is all it gets, later
+    // requests on a kept alive connection aren't followed
+    flow.inspecting = false;
+    openings.remove(src, dst);
+    flow.sni.is_some() || flow.http.is_some()
+}
+
+/// Name of the server end of a connection and where it was learned, the
+/// names the client asked for first
+fn server_domain(
+    domains: &mut Resolved,
+    packet: &ParsedPacket,
+    flow: Option<&Flow>,
+    now: Duration,
+) -> Option<(String, &'static str)> {
+    if let Some(flow) = flow {
+        if let Some(sni) = &flow.sni {
+            return Some((sni.clone(), "sni"));
+        }
+        if let Some(host) = flow.http.as_ref().and_then(|r| r.host.as_ref()) {
+            return Some((host.clone(), "http"));
+        }
+    }
+    let server = flow.map_or(packet.ip().dst, |f| f.responder.ip());
+    domains
+        .known_name(&server, now)
+        .map(|name| (name.name, name.source.as_str()))
+}
+
+/// Current time as a duration since the epoch, like capture timestamps
+fn wall_clock() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Builds the RST for a packet if its action calls for one
+fn reset_for(packet: &ParsedPacket, action: PacketAction) -> Option<Vec<u8>> {
+    let tcp = packet.tcp()?;
+    match action {
+        PacketAction::Reset if tcp.is_syn() || tcp.is_ack() => build_rst_packet_from(packet),
+        // syn_reset only refuses new connections, established flows are left alone
+        PacketAction::SynReset if tcp.is_syn() && !tcp.is_ack() => build_rst_packet_from(packet),
+        _ => None,
+    }
+}
+
+/// What the sniffer decided for one frame
+#[derive(Debug)]
+pub struct Decision {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub ports: Option<(u16, u16)>,
+    /// `None` for packets that aren't TCP or UDP
+    pub proto: Option<TransportProto>,
+    /// Missing on cooked captures
+    pub src_mac: Option<[u8; 6]>,
+    pub dst_mac: Option<[u8; 6]>,
+    /// Name of the server end and where it was learned, only looked up for
+    /// decisions worth an event
+    pub domain: Option<(String, &'static str)>,
+    /// Index of the first filter the packet matched, in evaluation order
+    pub filter: Option<usize>,
+    /// Id of that filter, its position in the config file
+    pub rule: Option<usize>,
+    pub action: PacketAction,
+    /// The filter waits on a reverse lookup, `action` comes from `on_unresolved`
+    pub unresolved: bool,
+    /// The filters were evaluated for this packet, not taken from the
+    /// match cached by its flow, or the packet tripped its flow's quota
+    pub first: bool,
+    /// RST packet to inject, if the connection should be reset
+    pub rst: Option<Vec<u8>>,
+    /// The RST should only be logged, not sent
+    pub dry_run: bool,
+}
+
+impl Decision {
+    /// Whether the decision goes to the event log: the first packet of a
+    /// monitored or reset connection, even when no RST could be built for
+    /// it, and any packet that gets a RST
+    pub fn is_event(&self) -> bool {
+        self.rst.is_some()
+            || (self.first
+                && matches!(
+                    self.action,
+                    PacketAction::Monitor | PacketAction::Reset | PacketAction::SynReset
+                ))
+    }
+}
+
+impl Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ports {
+            Some((src_port, dst_port)) => write!(
+                f,
+                "flow {} -> {}",
+                SocketAddr::new(self.src, src_port),
+                SocketAddr::new(self.dst, dst_port)
+            )?,
+            None => write!(f, "flow {} -> {}", self.src, self.dst)?,
+        }
+        match self.filter {
+            Some(idx) if self.unresolved => write!(
+                f,
+                " may match filter #{} once resolved, action {:?}",
+                idx + 1,
+                self.action
+            )?,
+            Some(idx) => write!(f, " matched filter #{}, action {:?}", idx + 1, self.action)?,
+            None => write!(f, " matched no filter")?,
+        }
+        let rst = self.rst.as_deref().map(ParsedPacket::parse);
+        if let Some(Ok(rst)) = rst {
+            if let Some(tcp) = rst.tcp() {
+                let kind = if tcp.is_ack() { "RST+ACK" } else { "RST" };
+                write!(
+                    f,
+                    ", would inject {kind} seq={} ack={}",
+                    tcp.seq_num, tcp.ack_num
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Decision;
+    use crate::filters::{PacketAction, TransportProto};
+
+    #[test]
+    fn decision_report_names_flow_and_filter() {
+        let decision = Decision {
+            src: "2001:db8::103".parse().unwrap(),
+            dst: "2001:db8::1".parse().unwrap(),
+            ports: Some((51000, 443)),
+            proto: Some(TransportProto::Tcp),
+            src_mac: None,
+            dst_mac: None,
+            domain: None,
+            filter: Some(2),
+            rule: Some(3),
+            action: PacketAction::Monitor,
+            unresolved: false,
+            first: true,
+            rst: None,
+            dry_run: false,
+        };
+        assert_eq!(
+            decision.to_string(),
+            "flow [2001:db8::103]:51000 -> [2001:db8::1]:443 matched filter #3, action Monitor"
+        );
+    }
+
+    #[test]
+    fn decision_report_without_match() {
+        let decision = Decision {
+            src: "10.0.0.1".parse().unwrap(),
+            dst: "10.0.0.2".parse().unwrap(),
+            ports: None,
+            proto: None,
+            src_mac: None,
+            dst_mac: None,
+            domain: None,
+            filter: None,
+            rule: None,
+            action: PacketAction::Ignore,
+            unresolved: false,
+            first: true,
+            rst: None,
+            dry_run: false,
+        };
+        assert_eq!(
+            decision.to_string(),
+            "flow 10.0.0.1 -> 10.0.0.2 matched no filter"
+        );
+    }
+}
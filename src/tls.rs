@@ -0,0 +1,238 @@
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const RECORD_HEADER_LEN: usize = 5;
+const EXT_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// What the first bytes a client sent say about the TLS handshake
+#[derive(Debug, PartialEq)]
+pub enum ClientHello {
+    /// The server name the client asked for
+    Sni(String),
+    /// A complete ClientHello without a server name extension
+    NoSni,
+    /// Looks like a ClientHello split across segments, more bytes needed
+    Incomplete,
+    NotTls,
+}
+
+/// Extracts the SNI from the start of a client's TCP stream, the ClientHello
+/// may span several segments and several TLS records
+pub fn parse_client_hello(stream: &[u8]) -> ClientHello {
+    // handshake messages can be fragmented over records, put them together
+    let mut handshake = Vec::new();
+    let mut rest = stream;
+    loop {
+        let Some(header) = rest.get(..RECORD_HEADER_LEN) else {
+            return ClientHello::Incomplete;
+        };
+        if header[0] != CONTENT_TYPE_HANDSHAKE || header[1] != 0x03 {
+            return ClientHello::NotTls;
+        }
+        let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        let Some(fragment) = rest.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len) else {
+            // whatever part of the record is there may already hold the SNI
+            handshake.extend_from_slice(&rest[RECORD_HEADER_LEN..]);
+            return match parse_handshake(&handshake) {
+                ClientHello::NoSni => ClientHello::Incomplete,
+                hello => hello,
+            };
+        };
+        handshake.extend_from_slice(fragment);
+        rest = &rest[RECORD_HEADER_LEN + len..];
+        match parse_handshake(&handshake) {
+            ClientHello::Incomplete => continue,
+            hello => return hello,
+        }
+    }
+}
+
+fn parse_handshake(handshake: &[u8]) -> ClientHello {
+    let mut r = Reader(handshake);
+    let Some(msg_type) = r.u8() else {
+        return ClientHello::Incomplete;
+    };
+    if msg_type != HANDSHAKE_CLIENT_HELLO {
+        return ClientHello::NotTls;
+    }
+    match client_hello_sni(&mut r) {
+        Some(Some(sni)) => ClientHello::Sni(sni),
+        Some(None) => ClientHello::NoSni,
+        None => ClientHello::Incomplete,
+    }
+}
+
+/// `None` if the message ends early, `Some(None)` if it has no SNI
+fn client_hello_sni(r: &mut Reader) -> Option<Option<String>> {
+    let len = r.take(3)?;
+    let len = usize::from(len[0]) << 16 | usize::from(len[1]) << 8 | usize::from(len[2]);
+    // only a complete message can be told to have no extensions
+    let (body, complete) = match r.take(len) {
+        Some(body) => (body, true),
+        None => (r.0, false),
+    };
+    let mut r = Reader(body);
+    r.skip(2 + 32)?; // legacy version and random
+    let session_id = r.u8()?;
+    r.skip(session_id.into())?;
+    let cipher_suites = r.u16()?;
+    r.skip(cipher_suites.into())?;
+    let compression = r.u8()?;
+    r.skip(compression.into())?;
+    if r.0.is_empty() {
+        // extensions are optional
+        return complete.then_some(None);
+    }
+    let extensions_len = r.u16()?;
+    // a truncated list may already hold the server name
+    let complete = r.take(extensions_len.into());
+    let mut extensions = Reader(complete.unwrap_or(r.0));
+    while let (Some(ext_type), Some(ext_len)) = (extensions.u16(), extensions.u16()) {
+        let data = extensions.take(ext_len.into());
+        if ext_type != EXT_SERVER_NAME {
+            data?;
+            continue;
+        }
+        let mut names = Reader(data?);
+        names.skip(2)?; // list length
+        while let Some(name_type) = names.u8() {
+            let len = names.u16()?;
+            let name = names.take(len.into())?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return Some(host_name(name));
+            }
+        }
+        return Some(None);
+    }
+    complete.map(|_| None)
+}
+
+fn host_name(name: &[u8]) -> Option<String> {
+    let name = std::str::from_utf8(name).ok()?;
+    let valid = name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b'_');
+    (valid && !name.is_empty()).then(|| name.to_ascii_lowercase())
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let taken = self.0.get(..n)?;
+        self.0 = &self.0[n..];
+        Some(taken)
+    }
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_client_hello, ClientHello};
+
+    /// TLS 1.2 style ClientHello in one record, with a padding extension
+    /// before the server name one
+    fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![0x00, 0x15, 0x00, 0x03, 0x00, 0x00, 0x00];
+        if let Some(sni) = sni {
+            let name = sni.as_bytes();
+            let len = name.len() as u16;
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&(len + 5).to_be_bytes());
+            extensions.extend_from_slice(&(len + 3).to_be_bytes());
+            extensions.push(0x00);
+            extensions.extend_from_slice(&len.to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0xab; 32]);
+        body.extend_from_slice(&[0x20]);
+        body.extend_from_slice(&[0xcd; 32]);
+        body.extend_from_slice(&[0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn sni_is_extracted() {
+        assert_eq!(
+            parse_client_hello(&client_hello(Some("WWW.Example.com"))),
+            ClientHello::Sni("www.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn hello_without_sni() {
+        assert_eq!(parse_client_hello(&client_hello(None)), ClientHello::NoSni);
+    }
+
+    #[test]
+    fn split_hello_needs_more_bytes() {
+        let hello = client_hello(Some("example.com"));
+        assert_eq!(parse_client_hello(&hello[..60]), ClientHello::Incomplete);
+        assert_eq!(parse_client_hello(&hello[..3]), ClientHello::Incomplete);
+    }
+
+    #[test]
+    fn hello_fragmented_over_records() {
+        let hello = client_hello(Some("example.com"));
+        let handshake = &hello[5..];
+        let mut records = Vec::new();
+        for part in [&handshake[..50], &handshake[50..]] {
+            records.extend_from_slice(&[0x16, 0x03, 0x01]);
+            records.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            records.extend_from_slice(part);
+        }
+        assert_eq!(
+            parse_client_hello(&records),
+            ClientHello::Sni("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn hello_split_before_its_extensions_needs_more_bytes() {
+        let hello = client_hello(Some("example.com"));
+        let handshake = &hello[5..];
+        // type, length, version, random, session id, cipher suites and
+        // compression methods
+        let split = 4 + 2 + 32 + 33 + 6 + 2;
+        let mut records = Vec::new();
+        for part in [&handshake[..split], &handshake[split..]] {
+            records.extend_from_slice(&[0x16, 0x03, 0x01]);
+            records.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            records.extend_from_slice(part);
+        }
+        assert_eq!(
+            parse_client_hello(&records[..5 + split]),
+            ClientHello::Incomplete
+        );
+        assert_eq!(
+            parse_client_hello(&records),
+            ClientHello::Sni("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn other_protocols_are_not_tls() {
+        assert_eq!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n"),
+            ClientHello::NotTls
+        );
+    }
+}